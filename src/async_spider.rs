@@ -0,0 +1,348 @@
+use close::CloseReason;
+use context::CrawlContext;
+use failure::Error;
+use futures::future::ok;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{Future, Stream};
+use request::Request;
+use reqwest::header::Headers;
+use response::Response;
+use spider::{Parse, ParseStream, RequestStream, Spider};
+use stats::StatsSnapshot;
+use std::fmt::Display;
+
+pub type SpiderFuture = Box<Future<Item = (), Error = Error>>;
+pub type ParseFuture = Box<Future<Item = (), Error = Error> + Send>;
+
+/// Sends requests and items from spider futures to the crawl.
+///
+/// The crawl waits for the future and every clone of its emitter,
+/// so the emitter must not be kept after the future resolves.
+pub struct Emitter<T: Send> {
+    sender: UnboundedSender<Parse<T>>,
+    context: CrawlContext,
+}
+
+impl<T: Send> Clone for Emitter<T> {
+    fn clone(&self) -> Self {
+        let sender = self.sender.clone();
+        let context = self.context.clone();
+        Emitter { sender, context }
+    }
+}
+
+#[allow(dead_code)]
+impl<T: Send> Emitter<T> {
+    fn new(context: CrawlContext) -> (Self, UnboundedReceiver<Parse<T>>) {
+        let (sender, receiver) = unbounded();
+        (Emitter { sender, context }, receiver)
+    }
+
+    /// Fetches auxiliary pages through the crawl, see `CrawlContext::fetch`.
+    pub fn context(&self) -> &CrawlContext {
+        &self.context
+    }
+
+    pub fn request(&self, request: Request) {
+        // the crawl is gone if sending fails, there is nobody to send to
+        let _ = self.sender.unbounded_send(Parse::Request(request));
+    }
+
+    pub fn item(&self, item: T) {
+        let _ = self.sender.unbounded_send(Parse::Item(item));
+    }
+}
+
+/// Spider which sends requests and items through an `Emitter` instead of
+/// building streams, run it with `AsyncSpiderAdapter`.
+pub trait AsyncSpider
+where
+    Self::Item: Sized + Display + Send + 'static,
+{
+    type Item;
+
+    fn name(&self) -> &'static str;
+
+    /// See `Spider::allowed_domains`.
+    fn allowed_domains(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// See `Spider::user_agent`.
+    fn user_agent(&self) -> Option<&str> {
+        None
+    }
+
+    /// See `Spider::default_headers`.
+    fn default_headers(&self) -> Headers {
+        Headers::new()
+    }
+
+    /// See `Spider::opened`.
+    fn opened(&mut self) -> SpiderFuture {
+        Box::new(ok(()))
+    }
+
+    /// See `Spider::closed`.
+    fn closed(&mut self, _reason: &CloseReason, _stats: &StatsSnapshot) {}
+
+    /// See `Spider::idle`, items emitted here are ignored.
+    fn idle(&mut self, _emitter: Emitter<Self::Item>) -> Option<SpiderFuture> {
+        None
+    }
+
+    /// Emits start requests, items emitted here are ignored.
+    fn start(&mut self, emitter: Emitter<Self::Item>) -> SpiderFuture;
+
+    fn parse(&mut self, response: Response, emitter: Emitter<Self::Item>) -> ParseFuture;
+}
+
+/// Runs an `AsyncSpider` as a `Spider`, so both styles work with the same `Crawler`.
+pub struct AsyncSpiderAdapter<S> {
+    spider: S,
+    context: Option<CrawlContext>,
+}
+
+#[allow(dead_code)]
+impl<S> AsyncSpiderAdapter<S> {
+    pub fn new(spider: S) -> Self {
+        let context = None;
+        AsyncSpiderAdapter { spider, context }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.spider
+    }
+}
+
+/// Stream of what the future emits, followed by its error if it fails.
+fn emitted<T, F>(
+    fut: F,
+    receiver: UnboundedReceiver<Parse<T>>,
+) -> impl Stream<Item = Result<Parse<T>, Error>, Error = Error>
+where
+    T: Send,
+    F: Future<Item = (), Error = Error>,
+{
+    let emitted = receiver
+        .map(Ok)
+        .map_err(|()| format_err!("emitter channel failed"));
+    let finished = fut.then(|result| -> Result<Option<Result<Parse<T>, Error>>, Error> {
+        Ok(result.err().map(Err))
+    });
+    let finished = finished.into_stream().filter_map(|error| error);
+    emitted.select(finished)
+}
+
+fn emitted_requests<T, F>(fut: F, receiver: UnboundedReceiver<Parse<T>>) -> RequestStream
+where
+    T: Send + 'static,
+    F: Future<Item = (), Error = Error> + 'static,
+{
+    let requests = emitted(fut, receiver).filter_map(|parse| match parse {
+        Ok(Parse::Request(request)) => Some(Ok(request)),
+        Ok(Parse::Item(_)) => None,
+        Err(e) => Some(Err(e)),
+    });
+    Box::new(requests)
+}
+
+impl<S> Spider for AsyncSpiderAdapter<S>
+where
+    S: AsyncSpider,
+{
+    type Item = S::Item;
+
+    fn name(&self) -> &'static str {
+        self.spider.name()
+    }
+
+    fn allowed_domains(&self) -> &[&'static str] {
+        self.spider.allowed_domains()
+    }
+
+    fn user_agent(&self) -> Option<&str> {
+        self.spider.user_agent()
+    }
+
+    fn default_headers(&self) -> Headers {
+        self.spider.default_headers()
+    }
+
+    fn opened(&mut self) -> Box<Future<Item = (), Error = Error>> {
+        self.spider.opened()
+    }
+
+    fn closed(&mut self, reason: &CloseReason, stats: &StatsSnapshot) {
+        self.spider.closed(reason, stats)
+    }
+
+    fn idle(&mut self) -> Option<RequestStream> {
+        // idle is only called after start, which sets the context
+        let context = self.context.clone()?;
+        let (emitter, receiver) = Emitter::new(context);
+        let fut = self.spider.idle(emitter)?;
+        Some(emitted_requests(fut, receiver))
+    }
+
+    fn start(&mut self, context: &CrawlContext) -> Box<Future<Item = RequestStream, Error = Error>> {
+        self.context = Some(context.clone());
+        let (emitter, receiver) = Emitter::new(context.clone());
+        let fut = self.spider.start(emitter);
+        Box::new(ok(emitted_requests(fut, receiver)))
+    }
+
+    fn parse(
+        &mut self,
+        response: Response,
+        context: &CrawlContext,
+    ) -> Box<Future<Item = ParseStream<Self::Item>, Error = Error> + Send> {
+        let (emitter, receiver) = Emitter::new(context.clone());
+        let fut = self.spider.parse(response, emitter);
+        // the parse stream is polled on the crawl thread, the future itself runs on the pool
+        let fut = match context.parse_pool() {
+            Some(pool) => Box::new(pool.spawn(fut)) as ParseFuture,
+            None => fut,
+        };
+        let parsed = Box::new(emitted(fut, receiver)) as ParseStream<Self::Item>;
+        Box::new(ok(parsed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crawler::{CrawlerBuilder, ParseSettings};
+    use futures::future::{err, lazy};
+    use reqwest::unstable::async::Client;
+    use reqwest::Method;
+    use sheduler::GlobalLimitedSheduler;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use test_server::{response, TestServer};
+    use tokio_core::reactor::Core;
+    use url::Url;
+
+    /// Emits the id of the thread its parse future runs on.
+    struct ThreadSpider {
+        url: Url,
+    }
+
+    impl AsyncSpider for ThreadSpider {
+        type Item = String;
+
+        fn name(&self) -> &'static str {
+            "thread"
+        }
+
+        fn start(&mut self, emitter: Emitter<String>) -> SpiderFuture {
+            emitter.request(Request::new(Method::Get, self.url.clone()));
+            Box::new(ok(()))
+        }
+
+        fn parse(&mut self, _response: Response, emitter: Emitter<String>) -> ParseFuture {
+            Box::new(lazy(move || {
+                emitter.item(format!("{:?}", thread::current().id()));
+                Ok(())
+            }))
+        }
+    }
+
+    /// Fetches the index in `start` and a detail page of every page in `parse`.
+    struct DetailSpider {
+        index: Url,
+    }
+
+    impl AsyncSpider for DetailSpider {
+        type Item = String;
+
+        fn name(&self) -> &'static str {
+            "detail"
+        }
+
+        fn start(&mut self, emitter: Emitter<String>) -> SpiderFuture {
+            let index = self.index.clone();
+            let fetch = emitter
+                .context()
+                .fetch(Request::new(Method::Get, index.clone()));
+            Box::new(fetch.and_then(move |response| {
+                for path in response.text().lines() {
+                    emitter.request(Request::new(Method::Get, index.join(path)?));
+                }
+                Ok(())
+            }))
+        }
+
+        fn parse(&mut self, response: Response, emitter: Emitter<String>) -> ParseFuture {
+            let detail = response.url().join(&format!("/detail{}", response.url().path()));
+            let detail = match detail {
+                Ok(detail) => detail,
+                Err(e) => return Box::new(err(e.into())),
+            };
+            let fetch = emitter.context().fetch(Request::new(Method::Get, detail));
+            Box::new(fetch.map(move |detail| emitter.item(detail.text().into_owned())))
+        }
+    }
+
+    #[test]
+    fn start_and_parse_fetch_pages() {
+        let server = TestServer::start(vec![
+            ("/index", response("200 OK", &[], "/a\n/b")),
+            ("/a", response("200 OK", &[], "a")),
+            ("/b", response("200 OK", &[], "b")),
+            ("/detail/a", response("200 OK", &[], "detail a")),
+            ("/detail/b", response("200 OK", &[], "detail b")),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let crawler = CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1))
+            .with_handle(core.handle())
+            .with_close_timeout(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        let spider = AsyncSpiderAdapter::new(DetailSpider {
+            index: server.url("/index"),
+        });
+        let items = Arc::new(Mutex::new(Vec::new()));
+        let collected = items.clone();
+        let crawl = crawler.crawl(spider).run(move |item| {
+            collected.lock().unwrap().push(item);
+            Ok(())
+        });
+        assert_eq!(core.run(crawl).unwrap(), CloseReason::Finished);
+        let mut items = items.lock().unwrap().clone();
+        items.sort();
+        assert_eq!(items, vec!["detail a", "detail b"]);
+    }
+
+    fn parse_threads(parse_settings: ParseSettings) -> Vec<String> {
+        let server = TestServer::start(vec![("/", response("200 OK", &[], "page"))]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let crawler = CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1))
+            .with_parse_settings(parse_settings)
+            .with_pool_size(1)
+            .build()
+            .unwrap();
+        let spider = AsyncSpiderAdapter::new(ThreadSpider { url: server.url("/") });
+        let threads = Arc::new(Mutex::new(Vec::new()));
+        let collected = threads.clone();
+        let crawl = crawler.crawl(spider).run(move |item| {
+            collected.lock().unwrap().push(item);
+            Ok(())
+        });
+        core.run(crawl).unwrap();
+        let threads = threads.lock().unwrap();
+        threads.clone()
+    }
+
+    #[test]
+    fn parse_runs_on_pool() {
+        let crawl_thread = format!("{:?}", thread::current().id());
+        let on_pool = parse_threads(ParseSettings::OnPool);
+        assert_eq!(on_pool.len(), 1);
+        assert_ne!(on_pool[0], crawl_thread);
+        assert_eq!(parse_threads(ParseSettings::SameThread), vec![crawl_thread]);
+    }
+}
@@ -3,6 +3,7 @@ use reqwest::unstable::async;
 use std::convert::From;
 use std::hash::{Hash, Hasher};
 
+#[derive(Clone)]
 pub struct Body {
     bytes: Bytes,
 }
@@ -10,7 +11,7 @@ pub struct Body {
 impl From<Vec<u8>> for Body {
     #[inline]
     fn from(v: Vec<u8>) -> Body {
-        v.into()
+        Body { bytes: v.into() }
     }
 }
 
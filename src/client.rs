@@ -0,0 +1,79 @@
+use failure::Error;
+use reqwest::unstable::async::{Client, ClientBuilder};
+use reqwest::{Proxy, RedirectPolicy};
+use socks::{socks_client, SocksClient};
+use tokio_core::reactor::Handle;
+use url::Url;
+
+/// Configuration of the http clients, the crawler builds its proxy and download
+/// worker clients with it so they behave like the sheduler client.
+///
+/// Timeouts are enforced by the downloader for every client, see `DownloadLimits`.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    max_redirects: Option<usize>,
+    gzip: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            max_redirects: None,
+            gzip: true,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl ClientConfig {
+    /// Redirects are not followed by default, use `RedirectMiddleware` for that.
+    pub fn with_max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = Some(max_redirects);
+        self
+    }
+
+    pub fn with_gzip(mut self, gzip: bool) -> Self {
+        self.gzip = gzip;
+        self
+    }
+
+    pub fn build(&self, handle: &Handle) -> Result<Client, Error> {
+        Ok(self.builder().build(handle)?)
+    }
+
+    /// Supports `http`, `https`, `socks5` and `socks5h` proxies. SOCKS clients don't
+    /// follow redirects, and they neither ask for nor decode gzip.
+    pub(crate) fn build_proxied(&self, proxy: &Url, handle: &Handle) -> Result<HttpClient, Error> {
+        match proxy.scheme() {
+            "http" | "https" => {
+                let mut builder = self.builder();
+                builder.proxy(Proxy::all(proxy.clone())?);
+                Ok(HttpClient::Reqwest(builder.build(handle)?))
+            }
+            "socks5" | "socks5h" => {
+                if self.max_redirects.is_some() {
+                    bail!("SOCKS proxy clients don't follow redirects, use RedirectMiddleware");
+                }
+                Ok(HttpClient::Socks(socks_client(proxy, handle)?))
+            }
+            scheme => bail!("proxy scheme {} is not supported", scheme),
+        }
+    }
+
+    fn builder(&self) -> ClientBuilder {
+        let mut builder = Client::builder();
+        let redirect = match self.max_redirects {
+            Some(max_redirects) => RedirectPolicy::limited(max_redirects),
+            None => RedirectPolicy::none(),
+        };
+        builder.redirect(redirect).gzip(self.gzip);
+        builder
+    }
+}
+
+/// Client a request is sent with.
+#[derive(Clone)]
+pub(crate) enum HttpClient {
+    Reqwest(Client),
+    Socks(SocksClient),
+}
@@ -0,0 +1,57 @@
+use stats::StatsSnapshot;
+use std::fmt;
+use std::time::Duration;
+
+/// Why a crawl ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CloseReason {
+    /// Every request was downloaded and parsed.
+    Finished,
+    ItemCount(u64),
+    PageCount(u64),
+    ErrorCount(u64),
+    Timeout(Duration),
+    /// Shutdown requested with `ShutdownHandle`, in progress work was finished.
+    Shutdown,
+    /// Forced shutdown or the shutdown deadline passed, in progress work was abandoned.
+    ShutdownForced,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CloseReason::Finished => write!(f, "finished"),
+            CloseReason::ItemCount(count) => write!(f, "{} items scraped", count),
+            CloseReason::PageCount(count) => write!(f, "{} pages downloaded", count),
+            CloseReason::ErrorCount(count) => write!(f, "{} downloads failed", count),
+            CloseReason::Timeout(timeout) => write!(f, "timeout of {:?} reached", timeout),
+            CloseReason::Shutdown => write!(f, "shutdown"),
+            CloseReason::ShutdownForced => write!(f, "forced shutdown"),
+        }
+    }
+}
+
+/// Limits after which a crawl is closed.
+///
+/// Closing stops sheduling new requests, downloads and parses in progress are finished
+/// and their items are still returned, so the counts can be slightly exceeded.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CloseConditions {
+    pub items: Option<u64>,
+    pub pages: Option<u64>,
+    pub errors: Option<u64>,
+    pub timeout: Option<Duration>,
+}
+
+impl CloseConditions {
+    /// Returns the reason to close the crawl if any count limit is reached,
+    /// the timeout is handled by the crawl with a timer.
+    pub fn reached(&self, stats: &StatsSnapshot) -> Option<CloseReason> {
+        match (self.items, self.pages, self.errors) {
+            (Some(items), _, _) if stats.items >= items => Some(CloseReason::ItemCount(items)),
+            (_, Some(pages), _) if stats.pages() >= pages => Some(CloseReason::PageCount(pages)),
+            (_, _, Some(errors)) if stats.errors >= errors => Some(CloseReason::ErrorCount(errors)),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use failure::Error;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
+use futures::Future;
+use futures_cpupool::CpuPool;
+use request::Request;
+use response::Response;
+
+/// Meta key with the id of a request fetched with `CrawlContext::fetch`,
+/// its response goes back to the fetch instead of `Spider::parse`.
+pub const FETCH_META_KEY: &str = "fetch";
+
+pub(crate) type FetchJob = (Request, oneshot::Sender<Response>);
+
+/// Lets spiders fetch requests through the crawl, can be sent to the parse pool.
+#[derive(Clone)]
+pub struct CrawlContext {
+    sender: UnboundedSender<FetchJob>,
+    pool: Option<CpuPool>,
+}
+
+impl CrawlContext {
+    /// `pool` is the parse pool, when parses run on it.
+    pub(crate) fn new(pool: Option<CpuPool>) -> (Self, UnboundedReceiver<FetchJob>) {
+        let (sender, receiver) = unbounded();
+        (CrawlContext { sender, pool }, receiver)
+    }
+
+    /// Pool parse futures are spawned on, `None` with `ParseSettings::SameThread`.
+    pub(crate) fn parse_pool(&self) -> Option<&CpuPool> {
+        self.pool.as_ref()
+    }
+
+    /// Fetches the request like any other request of the crawl, it goes through
+    /// the filters, the sheduler, downloader middlewares and stats.
+    ///
+    /// Fails if the request is dropped, e.g. as a duplicate, set `dont_filter`
+    /// to fetch an url again. Fetches go before other requests, but no responses
+    /// are taken while `max_pending_parses` is reached, so parses waiting for
+    /// fetches must stay below it.
+    pub fn fetch(&self, request: Request) -> Box<Future<Item = Response, Error = Error> + Send> {
+        let url = request.url().clone();
+        let (reply, response) = oneshot::channel();
+        // failing to send means the crawl is gone, the dropped reply fails the fetch
+        let _ = self.sender.unbounded_send((request, reply));
+        Box::new(response.map_err(move |_| format_err!("request to {} was dropped", url)))
+    }
+}
@@ -0,0 +1,375 @@
+use downloader::{DownloaderMiddleware, RequestAction, ResponseAction};
+use request::Request;
+use reqwest::header::{HttpDate, Raw};
+use response::Response;
+use std::collections::HashMap;
+use std::str;
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+/// Meta key selecting the cookie jar of a request, requests without it share the default jar.
+pub const COOKIE_JAR_META_KEY: &str = "cookiejar";
+
+/// Common public suffixes with two labels, every single label domain is a public suffix too.
+/// This is not the full public suffix list.
+const PUBLIC_SUFFIXES: &[&str] = &[
+    "ac.uk", "co.uk", "gov.uk", "org.uk", "com.au", "net.au", "org.au", "co.jp", "co.nz",
+    "com.br", "com.cn", "co.in",
+];
+
+struct Cookie {
+    name: String,
+    value: String,
+    domain: String,
+    host_only: bool,
+    path: String,
+    secure: bool,
+    expires: Option<SystemTime>,
+}
+
+impl Cookie {
+    /// Parses a `Set-Cookie` header value received from `url`.
+    fn parse(header: &str, url: &Url) -> Option<Cookie> {
+        let host = url.host_str()?.to_lowercase();
+        let mut attributes = header.split(';');
+        let mut pair = attributes.next()?.splitn(2, '=');
+        let name = pair.next()?.trim().to_owned();
+        let value = pair.next()?.trim().to_owned();
+        if name.is_empty() {
+            return None;
+        }
+
+        let mut cookie = Cookie {
+            name,
+            value,
+            domain: host.clone(),
+            host_only: true,
+            path: default_path(url),
+            secure: false,
+            expires: None,
+        };
+        for attribute in attributes {
+            let mut split = attribute.splitn(2, '=');
+            let key = split.next().unwrap_or("").trim().to_lowercase();
+            let value = split.next().unwrap_or("").trim();
+            match key.as_str() {
+                "domain" if !value.is_empty() => {
+                    let domain = value.trim_left_matches('.').to_lowercase();
+                    if !domain_matches(&host, &domain) {
+                        return None;
+                    }
+                    // a public suffix is only allowed as the host itself, RFC 6265 section 5.3
+                    if is_public_suffix(&domain) {
+                        if domain != host {
+                            return None;
+                        }
+                        continue;
+                    }
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_owned(),
+                "secure" => cookie.secure = true,
+                "max-age" => {
+                    if let Ok(seconds) = value.parse::<i64>() {
+                        cookie.expires = if seconds > 0 {
+                            Some(SystemTime::now() + Duration::from_secs(seconds as u64))
+                        } else {
+                            Some(SystemTime::now())
+                        };
+                    }
+                }
+                "expires" if cookie.expires.is_none() => {
+                    if let Ok(date) = value.parse::<HttpDate>() {
+                        cookie.expires = Some(date.into());
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(cookie)
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        match self.expires {
+            Some(expires) => expires <= now,
+            None => false,
+        }
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return false,
+        };
+        let domain_ok = if self.host_only {
+            host == self.domain
+        } else {
+            domain_matches(&host, &self.domain)
+        };
+        domain_ok && path_matches(url.path(), &self.path) && (!self.secure || url.scheme() == "https")
+    }
+}
+
+fn is_public_suffix(domain: &str) -> bool {
+    !domain.contains('.') || PUBLIC_SUFFIXES.contains(&domain)
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.'))
+}
+
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || (path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+/// Directory of the request path, as described in RFC 6265 section 5.1.4.
+fn default_path(url: &Url) -> String {
+    let path = url.path();
+    match path.rfind('/') {
+        Some(0) | None => "/".to_owned(),
+        Some(pos) => path[..pos].to_owned(),
+    }
+}
+
+#[derive(Default)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+#[allow(dead_code)]
+impl CookieJar {
+    /// Stores cookies from the `Set-Cookie` headers of the response.
+    pub fn extract(&mut self, response: &Response) {
+        let raw = match response.headers().get_raw("Set-Cookie") {
+            Some(raw) => raw,
+            None => return,
+        };
+        for line in raw.iter() {
+            let line = match str::from_utf8(line) {
+                Ok(line) => line,
+                Err(_) => continue,
+            };
+            if let Some(cookie) = Cookie::parse(line, response.url()) {
+                self.store(cookie);
+            }
+        }
+    }
+
+    fn store(&mut self, cookie: Cookie) {
+        self.cookies.retain(|stored| {
+            stored.name != cookie.name || stored.domain != cookie.domain || stored.path != cookie.path
+        });
+        if !cookie.is_expired(SystemTime::now()) {
+            self.cookies.push(cookie);
+        }
+    }
+
+    /// Value for the `Cookie` header of a request to the url, longer paths go first.
+    pub fn header_for(&mut self, url: &Url) -> Option<String> {
+        let now = SystemTime::now();
+        self.cookies.retain(|cookie| !cookie.is_expired(now));
+        let mut matching: Vec<&Cookie> = self.cookies.iter().filter(|c| c.matches(url)).collect();
+        if matching.is_empty() {
+            return None;
+        }
+        matching.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+        let pairs: Vec<String> = matching
+            .iter()
+            .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+            .collect();
+        Some(pairs.join("; "))
+    }
+
+    pub fn len(&self) -> usize {
+        self.cookies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.cookies.clear();
+    }
+}
+
+/// Keeps cookies received in responses and sends them with matching requests.
+///
+/// Every request uses the jar named by its `COOKIE_JAR_META_KEY` meta,
+/// so several logged in sessions can run in one crawl.
+#[derive(Default)]
+pub struct CookiesMiddleware {
+    jars: HashMap<String, CookieJar>,
+}
+
+#[allow(dead_code)]
+impl CookiesMiddleware {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn jar(&self, name: &str) -> Option<&CookieJar> {
+        self.jars.get(name)
+    }
+
+    pub fn jar_mut(&mut self, name: &str) -> &mut CookieJar {
+        self.jars.entry(name.to_owned()).or_insert_with(CookieJar::default)
+    }
+}
+
+/// Rebuilds the `Cookie` header from the cookies already set on the request and the jar,
+/// jar cookies replace request cookies of the same name, e.g. when a request is sheduled again.
+fn merge_cookie_header(existing: Option<&Raw>, jar: &str) -> String {
+    let name = |pair: &str| pair.split('=').next().unwrap_or("").trim().to_owned();
+    let jar_names: Vec<String> = jar.split(';').map(&name).collect();
+    let mut pairs: Vec<String> = Vec::new();
+    if let Some(existing) = existing {
+        for line in existing.iter().filter_map(|line| str::from_utf8(line).ok()) {
+            for pair in line.split(';').map(str::trim).filter(|pair| !pair.is_empty()) {
+                if !jar_names.contains(&name(pair)) {
+                    pairs.push(pair.to_owned());
+                }
+            }
+        }
+    }
+    pairs.push(jar.to_owned());
+    pairs.join("; ")
+}
+
+fn jar_name(request: &Request) -> &str {
+    request
+        .meta()
+        .get(COOKIE_JAR_META_KEY)
+        .map(|name| name.as_str())
+        .unwrap_or("")
+}
+
+impl DownloaderMiddleware for CookiesMiddleware {
+    fn name(&self) -> &'static str {
+        "CookiesMiddleware"
+    }
+
+    fn process_request(&mut self, mut request: Request) -> RequestAction {
+        let header = {
+            let jar = self.jar_mut(jar_name(&request));
+            jar.header_for(request.url())
+        };
+        if let Some(header) = header {
+            let header = merge_cookie_header(request.headers().get_raw("Cookie"), &header);
+            request.headers_mut().set_raw("Cookie", header);
+        }
+        RequestAction::Continue(request)
+    }
+
+    fn process_response(&mut self, response: Response) -> ResponseAction {
+        self.jar_mut(jar_name(response.request())).extract(&response);
+        ResponseAction::Continue(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use reqwest::header::Headers;
+    use reqwest::{Method, StatusCode};
+
+    fn url(url: &str) -> Url {
+        url.parse().unwrap()
+    }
+
+    fn response(from: &str, set_cookies: &[&str]) -> Response {
+        let mut headers = Headers::new();
+        for cookie in set_cookies {
+            headers.append_raw("Set-Cookie", cookie.to_string());
+        }
+        let request = Request::new(Method::Get, url(from));
+        Response::new(url(from), StatusCode::Ok, headers, Bytes::new(), request)
+    }
+
+    fn jar_with(from: &str, set_cookies: &[&str]) -> CookieJar {
+        let mut jar = CookieJar::default();
+        jar.extract(&response(from, set_cookies));
+        jar
+    }
+
+    #[test]
+    fn domain_and_path_matching() {
+        let mut jar = jar_with(
+            "http://www.example.com/shop/cart",
+            &["a=1", "b=2; Domain=.Example.com; Path=/", "c=3; Domain=other.com"],
+        );
+        assert_eq!(jar.len(), 2);
+        let mut header = |to: &str| jar.header_for(&url(to));
+        assert_eq!(header("http://www.example.com/shop/item"), Some("a=1; b=2".to_owned()));
+        assert_eq!(header("http://www.example.com/shopping"), Some("b=2".to_owned()));
+        assert_eq!(header("http://sub.example.com/shop"), Some("b=2".to_owned()));
+        assert_eq!(header("http://example.com/"), Some("b=2".to_owned()));
+        assert_eq!(header("http://badexample.com/"), None);
+    }
+
+    #[test]
+    fn public_suffix_domains_are_rejected() {
+        let jar = jar_with("http://www.example.com/", &["a=1; Domain=com", "b=2; Domain=.com"]);
+        assert!(jar.is_empty());
+        let jar = jar_with("http://shop.example.co.uk/", &["a=1; Domain=co.uk"]);
+        assert!(jar.is_empty());
+        // a single label host may set a host only cookie for itself
+        let mut jar = jar_with("http://localhost/", &["a=1; Domain=localhost"]);
+        assert_eq!(jar.header_for(&url("http://localhost/")), Some("a=1".to_owned()));
+    }
+
+    #[test]
+    fn expired_cookies_are_removed() {
+        let mut jar = jar_with("http://example.com/", &["a=1; Max-Age=0", "b=2; Max-Age=100"]);
+        assert_eq!(jar.header_for(&url("http://example.com/")), Some("b=2".to_owned()));
+        jar.extract(&response(
+            "http://example.com/",
+            &["b=2; Expires=Thu, 01 Jan 1970 00:00:00 GMT"],
+        ));
+        assert_eq!(jar.header_for(&url("http://example.com/")), None);
+        assert!(jar.is_empty());
+    }
+
+    #[test]
+    fn secure_cookies_are_only_sent_over_https() {
+        let mut jar = jar_with("https://example.com/", &["a=1; Secure", "b=2"]);
+        assert_eq!(jar.header_for(&url("http://example.com/")), Some("b=2".to_owned()));
+        assert_eq!(jar.header_for(&url("https://example.com/")), Some("a=1; b=2".to_owned()));
+    }
+
+    #[test]
+    fn cookie_with_same_name_is_replaced() {
+        let mut jar = jar_with("http://example.com/", &["a=1"]);
+        jar.extract(&response("http://example.com/", &["a=2"]));
+        assert_eq!(jar.len(), 1);
+        assert_eq!(jar.header_for(&url("http://example.com/")), Some("a=2".to_owned()));
+        // another path is another cookie
+        jar.extract(&response("http://example.com/", &["a=3; Path=/shop"]));
+        let header = jar.header_for(&url("http://example.com/shop"));
+        assert_eq!(header, Some("a=3; a=2".to_owned()));
+    }
+
+    #[test]
+    fn cookie_header_is_rebuilt() {
+        let mut middleware = CookiesMiddleware::new();
+        middleware.process_response(response("http://example.com/", &["a=1"]));
+        let mut request = Request::new(Method::Get, url("http://example.com/"));
+        request.headers_mut().set_raw("Cookie", "user=me; a=old");
+        let request = match middleware.process_request(request) {
+            RequestAction::Continue(request) => request,
+            _ => panic!("request was not continued"),
+        };
+        // a request sheduled again doesn't get the jar cookies twice
+        let request = match middleware.process_request(request) {
+            RequestAction::Continue(request) => request,
+            _ => panic!("request was not continued"),
+        };
+        let header = request.headers().get_raw("Cookie").unwrap().one().unwrap().to_vec();
+        assert_eq!(String::from_utf8(header).unwrap(), "user=me; a=1");
+    }
+}
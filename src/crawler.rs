@@ -1,25 +1,62 @@
+use client::ClientConfig;
+use close::{CloseConditions, CloseReason};
+use context::{CrawlContext, FetchJob, FETCH_META_KEY};
+use downloader::{DefaultHeaders, DownloadLimits, Downloader, DownloaderMiddleware};
 use eos_on_error::EosOnErrorExt;
-use ex_futures::stream::StreamExt;
+use events::{Event, EventBus};
 use failure::Error;
-use futures::stream::FuturesUnordered;
-use futures::{Async, Future, Poll, Stream};
+use futures::future::{loop_fn, Either, Loop};
+use futures::stream::{iter_ok, FuturesUnordered};
+use futures::sync::mpsc::UnboundedReceiver;
+use futures::sync::oneshot;
+use futures::task;
+use futures::{Async, Future, IntoFuture, Poll, Stream};
 use futures_cpupool::CpuPool;
+use item_filter::ItemFilter;
+use offsite::OffsiteFilter;
+use proxy::ProxyClients;
+use request::Request;
+use response::Response;
+use reqwest::header::{Headers, Raw};
 use select_all::SelectAll;
+use settings::{seconds, Settings};
+use shutdown::{Shutdown, ShutdownHandle};
 use sheduler::*;
 use slog::Logger;
 use spider::*;
-use std::cell::RefCell;
+use spider_middleware::{SpiderMiddleware, SpiderMiddlewares};
+use stats::{Stats, DROP_DUPLICATE_ITEM, DROP_OFFSITE, DROP_SPIDER_MIDDLEWARE};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
 use utils::{filter_and_log_errors, get_digest_and_request, RFPFilter};
+use wake_fork::WakeForkExt;
+use workers::DownloadWorkers;
+
+/// `User-Agent` sent unless the crawler or the spider set another one.
+pub const DEFAULT_USER_AGENT: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
 pub struct Crawler<SH>
 where
     SH: Sheduler,
 {
     sheduler: Rc<RefCell<SH>>,
+    default_headers: Rc<DefaultHeaders>,
     logger: Option<Logger>,
     pool: CpuPool,
     parse_settings: ParseSettings,
+    request_buffer: usize,
+    backpressure: Backpressure,
+    stats: Stats,
+    events: EventBus,
+    close_conditions: CloseConditions,
+    shutdown_deadline: Option<Duration>,
+    handle: Option<Handle>,
 }
 
 pub struct Crawl<S, SH>
@@ -27,19 +64,329 @@ where
     S: Spider,
 {
     spider: S,
+    opening: Option<Box<Future<Item = (), Error = Error>>>,
+    context: CrawlContext,
+    fetch_requests: UnboundedReceiver<FetchJob>,
+    fetches: HashMap<u64, oneshot::Sender<Response>>,
+    next_fetch_id: u64,
     sheduler: Rc<RefCell<SH>>,
-    parsing: FuturesUnordered<Box<Future<Item = ParseStream<S::Item>, Error = Error>>>,
+    parsing: FuturesUnordered<
+        Box<Future<Item = (Rc<Response>, ParseStream<S::Item>), Error = Error>>,
+    >,
     output: SelectAll<ItemStream<S::Item>>,
     logger: Option<Logger>,
     pool: CpuPool,
     parse_settings: ParseSettings,
+    request_buffer: usize,
+    backpressure: Backpressure,
+    pending_items: Rc<Cell<usize>>,
+    pending_requests: Rc<Cell<usize>>,
     rfp_filter: RFPFilter,
+    offsite_filter: Rc<RefCell<OffsiteFilter>>,
+    item_filter: Option<ItemFilter<S::Item>>,
+    spider_middlewares: SpiderMiddlewares<S::Item>,
+    stats: Stats,
+    events: EventBus,
+    close_conditions: CloseConditions,
+    close_timer: Option<Timeout>,
+    close_reason: Option<CloseReason>,
+    shutdown: ShutdownHandle,
+    shutdown_requests: UnboundedReceiver<Shutdown>,
+    shutdown_deadline: Option<Duration>,
+    shutdown_timer: Option<Timeout>,
+    forced: bool,
+    handle: Option<Handle>,
 }
 
+#[allow(dead_code)]
 impl<S, SH> Crawl<S, SH>
 where
     S: Spider,
 {
+    /// Drops items already produced by this crawl, see `ItemFilter`.
+    pub fn with_item_filter(mut self, filter: ItemFilter<S::Item>) -> Self {
+        self.item_filter = Some(filter);
+        self
+    }
+
+    /// Appends a middleware to the spider chain, see `SpiderMiddleware`.
+    pub fn with_spider_middleware<M>(mut self, middleware: M) -> Self
+    where
+        M: SpiderMiddleware<S::Item> + 'static,
+    {
+        self.spider_middlewares.push(Box::new(middleware));
+        self
+    }
+
+    pub fn spider(&self) -> &S {
+        &self.spider
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Why the crawl is closed, set when a close condition is reached
+    /// or when the crawl finishes.
+    pub fn close_reason(&self) -> Option<&CloseReason> {
+        self.close_reason.as_ref()
+    }
+
+    /// Runs the crawl to the end, passing every item to `f`,
+    /// resolves to the reason the crawl was closed.
+    pub fn run<'a, F, U>(self, f: F) -> Box<Future<Item = CloseReason, Error = Error> + 'a>
+    where
+        S: 'a,
+        SH: Sheduler + 'a,
+        F: FnMut(S::Item) -> U + 'a,
+        U: IntoFuture<Item = (), Error = Error> + 'a,
+    {
+        let fut = loop_fn((self, f), |(crawl, mut f)| {
+            crawl
+                .into_future()
+                .map_err(|(e, _)| e)
+                .and_then(|(item, crawl)| match item {
+                    Some(item) => Either::A(
+                        f(item)
+                            .into_future()
+                            .map(move |()| Loop::Continue((crawl, f))),
+                    ),
+                    None => {
+                        let reason = crawl.close_reason.clone().unwrap_or(CloseReason::Finished);
+                        Either::B(Ok(Loop::Break(reason)).into_future())
+                    }
+                })
+        });
+        Box::new(fut)
+    }
+
+    pub(crate) fn sheduler(&self) -> Rc<RefCell<SH>> {
+        self.sheduler.clone()
+    }
+
+    /// Handle to shut the crawl down from elsewhere, e.g. a signal handler.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    fn close(&mut self, reason: CloseReason)
+    where
+        SH: Sheduler,
+    {
+        if let Some(ref logger) = self.logger {
+            info!(logger, "closing crawl"; "reason" => %reason);
+        }
+        self.sheduler.borrow_mut().close();
+        self.close_reason = Some(reason);
+    }
+
+    /// Ends the crawl without waiting for downloads and parses in progress.
+    fn force_close(&mut self)
+    where
+        SH: Sheduler,
+    {
+        if let Some(ref logger) = self.logger {
+            info!(logger, "crawl closed without finishing downloads and parses in progress");
+        }
+        self.sheduler.borrow_mut().close();
+        self.close_reason = Some(CloseReason::ShutdownForced);
+        self.forced = true;
+    }
+
+    fn check_shutdown(&mut self) -> Result<(), Error>
+    where
+        SH: Sheduler,
+    {
+        while let Ok(Async::Ready(Some(request))) = self.shutdown_requests.poll() {
+            match request {
+                Shutdown::Graceful => {
+                    if self.close_reason.is_none() {
+                        self.close(CloseReason::Shutdown);
+                    }
+                    if let (Some(deadline), &Some(ref handle)) = (self.shutdown_deadline, &self.handle)
+                    {
+                        if self.shutdown_timer.is_none() {
+                            self.shutdown_timer = Some(Timeout::new(deadline, handle)?);
+                        }
+                    }
+                }
+                Shutdown::Forced => self.force_close(),
+            }
+        }
+        let expired = match self.shutdown_timer {
+            Some(ref mut timer) => timer.poll()?.is_ready(),
+            None => false,
+        };
+        if expired {
+            self.force_close();
+        }
+        Ok(())
+    }
+
+    fn start_requests(&mut self)
+    where
+        SH: Sheduler,
+    {
+        let start_stream = self.spider.start(&self.context).flatten_stream();
+        let start_stream =
+            filter_and_log_errors(start_stream, &self.logger).eos_on_error(&self.logger);
+        self.shedule_requests(start_stream);
+    }
+
+    /// Asks the spider for more requests, returns `false` if it has none.
+    fn idle(&mut self) -> bool
+    where
+        SH: Sheduler,
+    {
+        let spider = self.spider.name();
+        self.events.emit(|| Event::SpiderIdle { spider });
+        if self.close_reason.is_some() {
+            return false;
+        }
+        match self.spider.idle() {
+            Some(requests) => {
+                if let Some(ref logger) = self.logger {
+                    debug!(logger, "spider idle, sheduling more requests");
+                }
+                let requests =
+                    filter_and_log_errors(requests, &self.logger).eos_on_error(&self.logger);
+                self.shedule_requests(requests);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn finish(&mut self) {
+        self.stats.finish();
+        let snapshot = self.stats.snapshot();
+        // the last download or item may reach a condition after it was checked
+        let reason = self.close_reason
+            .take()
+            .or_else(|| self.close_conditions.reached(&snapshot))
+            .unwrap_or(CloseReason::Finished);
+        self.spider.closed(&reason, &snapshot);
+        let spider = self.spider.name();
+        self.events.emit(|| Event::SpiderClosed {
+            spider,
+            reason: reason.clone(),
+        });
+        self.close_reason = Some(reason);
+        if let Some(ref logger) = self.logger {
+            if let Some(ref reason) = self.close_reason {
+                info!(logger, "crawl closed"; "reason" => %reason);
+            }
+            for (host, count) in self.offsite_filter.borrow().dropped() {
+                info!(logger, "offsite requests filtered"; "host" => host, "count" => count);
+            }
+            snapshot.log(logger);
+        }
+    }
+
+    fn check_close_conditions(&mut self) -> Result<(), Error>
+    where
+        SH: Sheduler,
+    {
+        if self.close_reason.is_some() {
+            return Ok(());
+        }
+        let mut reason = self.close_conditions.reached(&self.stats.snapshot());
+        if let (Some(timeout), &Some(ref handle)) = (self.close_conditions.timeout, &self.handle) {
+            if self.close_timer.is_none() {
+                self.close_timer = Some(Timeout::new(timeout, handle)?);
+            }
+            if let Some(ref mut timer) = self.close_timer {
+                if let Async::Ready(()) = timer.poll()? {
+                    reason = Some(CloseReason::Timeout(timeout));
+                }
+            }
+        }
+        if let Some(reason) = reason {
+            self.close(reason);
+        }
+        Ok(())
+    }
+
+    fn shedule_requests<R>(&self, requests: R)
+    where
+        R: Stream<Item = Request, Error = !> + 'static,
+        SH: Sheduler,
+    {
+        if self.close_reason.is_some() {
+            return;
+        }
+        let requests = self.filter_requests(requests);
+        self.sheduler.borrow_mut().shedule(requests);
+    }
+
+    fn shedule_fetches<R>(&self, requests: R)
+    where
+        R: Stream<Item = Request, Error = !> + 'static,
+        SH: Sheduler,
+    {
+        if self.close_reason.is_some() {
+            return;
+        }
+        let requests = self.filter_requests(requests);
+        self.sheduler.borrow_mut().shedule_fetches(requests);
+    }
+
+    /// Drops offsite and duplicate requests.
+    fn filter_requests<R>(&self, requests: R) -> InternalRequestStream
+    where
+        R: Stream<Item = Request, Error = !> + 'static,
+    {
+        let pool = self.pool.clone();
+        let offsite_filter = self.offsite_filter.clone();
+        let stats = self.stats.clone();
+        let events = self.events.clone();
+        let requests = requests
+            .filter(move |req| {
+                let allowed = offsite_filter.borrow_mut().filter(req);
+                if !allowed {
+                    stats.dropped(DROP_OFFSITE);
+                    events.emit(|| Event::RequestDropped {
+                        url: Some(req.url().clone()),
+                        reason: DROP_OFFSITE,
+                    });
+                }
+                allowed
+            })
+            .map(move |req| pool.spawn_fn(|| Ok(get_digest_and_request(req))))
+            .buffered(self.request_buffer);
+        Box::new(self.rfp_filter.unique(requests))
+    }
+
+    fn is_duplicate_item(&mut self, item: &S::Item) -> bool {
+        let filter = match self.item_filter {
+            Some(ref mut filter) => filter,
+            None => return false,
+        };
+        match filter.is_duplicate(item) {
+            Ok(true) => {
+                self.stats.dropped(DROP_DUPLICATE_ITEM);
+                self.events.emit(|| Event::ItemDropped {
+                    item: item.to_string(),
+                    reason: DROP_DUPLICATE_ITEM,
+                });
+                if let Some(ref logger) = self.logger {
+                    info!(logger, "item filtered"; "item" => %item);
+                }
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                if let Some(ref logger) = self.logger {
+                    error!(logger, "failed to remember item"; "item" => %item, "error" => %e);
+                }
+                false
+            }
+        }
+    }
     fn wrap_parse_future(
         &self,
         fut: Box<Future<Item = ParseStream<S::Item>, Error = Error> + Send>,
@@ -54,6 +401,40 @@ where
     }
 }
 
+/// Bounds of the work buffered by a crawl, `None` means unbounded.
+///
+/// When the item consumer falls behind and `items` is reached, downloads and parses
+/// are paused until the consumer catches up. When `parses` is reached, no new
+/// responses are taken. When `requests` produced by parses wait to be sheduled,
+/// parse results are not taken, unless parses are also full: requests are only
+/// sheduled when responses are taken, so the parses in progress are finished first
+/// and `requests` can be exceeded by the requests they produce.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Backpressure {
+    pub parses: Option<usize>,
+    pub items: Option<usize>,
+    pub requests: Option<usize>,
+}
+
+impl Backpressure {
+    /// Whether responses and parse results are taken with this much pending work.
+    fn flow(&self, items: usize, parses: usize, requests: usize) -> (bool, bool) {
+        let items_full = is_full(items, self.items);
+        let parses_full = is_full(parses, self.parses);
+        let requests_full = is_full(requests, self.requests);
+        let take_responses = !items_full && !parses_full;
+        let take_parses = !items_full && (!requests_full || parses_full);
+        (take_responses, take_parses)
+    }
+}
+
+fn is_full(pending: usize, bound: Option<usize>) -> bool {
+    match bound {
+        Some(bound) => pending >= bound,
+        None => false,
+    }
+}
+
 impl<S, SH> Stream for Crawl<S, SH>
 where
     S: Spider,
@@ -63,35 +444,139 @@ where
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        {
+        self.check_shutdown()?;
+        if self.forced {
+            self.finish();
+            return Ok(Async::Ready(None));
+        }
+        if let Some(mut opening) = self.opening.take() {
+            match opening.poll()? {
+                Async::Ready(()) => {
+                    let spider = self.spider.name();
+                    self.events.emit(|| Event::SpiderOpened { spider });
+                    self.start_requests();
+                }
+                Async::NotReady => {
+                    self.opening = Some(opening);
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+        self.check_close_conditions()?;
+
+        let mut fetched = Vec::new();
+        while let Ok(Async::Ready(Some((mut request, reply)))) = self.fetch_requests.poll() {
+            let id = self.next_fetch_id;
+            self.next_fetch_id += 1;
+            request
+                .meta_mut()
+                .insert(FETCH_META_KEY.to_owned(), id.to_string());
+            self.fetches.insert(id, reply);
+            fetched.push(request);
+        }
+        if !fetched.is_empty() {
+            self.shedule_fetches(iter_ok(fetched));
+        }
+
+        // requests are not sheduled anymore after closing, so they are not pending
+        let pending_requests = match self.close_reason {
+            Some(_) => 0,
+            None => self.pending_requests.get(),
+        };
+        let (take_responses, take_parses) = self.backpressure.flow(
+            self.pending_items.get(),
+            self.parsing.len(),
+            pending_requests,
+        );
+
+        let rescheduled = {
             let mut sheduler = self.sheduler.borrow_mut();
-            if let Async::Ready(Some(resp)) = sheduler.poll()? {
-                let parse_fut = self.spider.parse(resp);
-                let parse_fut = self.wrap_parse_future(parse_fut);
-                self.parsing.push(Box::new(parse_fut));
+            let polled = if take_responses {
+                sheduler.poll()?
+            } else {
+                Async::NotReady
+            };
+            let polled = match polled {
+                Async::Ready(Some(resp)) => {
+                    let fetches = &mut self.fetches;
+                    let fetch = resp.request()
+                        .meta()
+                        .get(FETCH_META_KEY)
+                        .and_then(|id| id.parse::<u64>().ok())
+                        .and_then(|id| fetches.remove(&id));
+                    match fetch {
+                        Some(reply) => {
+                            // the fetch may be gone, e.g. its parse failed
+                            let _ = reply.send(resp);
+                            // fetches are sent before their future is polled, so the
+                            // reply may wake nobody, the crawl polls the spider again
+                            task::current().notify();
+                            None
+                        }
+                        None => Some(resp),
+                    }
+                }
+                _ => None,
+            };
+            if let Some(resp) = polled {
+                match self.spider_middlewares.process_input(&resp) {
+                    Ok(()) => {
+                        let response = Rc::new(resp.clone());
+                        let parse_fut = self.spider.parse(resp, &self.context);
+                        let parse_fut = self.wrap_parse_future(parse_fut);
+                        let parse_fut = parse_fut.map(move |parsed| (response, parsed));
+                        self.parsing.push(Box::new(parse_fut));
+                    }
+                    Err(e) => {
+                        self.stats.dropped(DROP_SPIDER_MIDDLEWARE);
+                        if let Some(ref logger) = self.logger {
+                            info!(logger, "response skipped"; "response" => %resp, "reason" => %e);
+                        }
+                        // nothing else may wake the crawl, the sheduler can have more responses
+                        task::current().notify();
+                    }
+                }
             }
+            sheduler.take_rescheduled()
+        };
+
+        if !rescheduled.is_empty() {
+            self.shedule_requests(iter_ok(rescheduled));
         }
 
-        if let Async::Ready(Some(parsed)) = self.parsing.poll()? {
+        // nothing is downloading, so pending fetches were dropped by a filter or a middleware
+        if !self.fetches.is_empty() && self.sheduler.borrow().is_done() {
+            self.fetches.clear();
+        }
+
+        let parsed = if take_parses {
+            self.parsing.poll()?
+        } else {
+            Async::NotReady
+        };
+        if let Async::Ready(Some((response, parsed))) = parsed {
             let parsed = filter_and_log_errors(parsed, &self.logger).eos_on_error(&self.logger);
-            let (new_requests, new_items) = parsed.unsync_fork(|item| match item {
+            let parsed = self.spider_middlewares.process_output(response, parsed);
+            let pending_items = self.pending_items.clone();
+            let pending_requests = self.pending_requests.clone();
+            let parsed = parsed.inspect(move |parse| match parse {
+                &Parse::Request(_) => pending_requests.set(pending_requests.get() + 1),
+                &Parse::Item(_) => pending_items.set(pending_items.get() + 1),
+            });
+            let (new_requests, new_items) = parsed.wake_fork(|item| match item {
                 &Parse::Request(_) => true,
                 _ => false,
             });
 
-            let new_requests = new_requests.map(|item| match item {
-                Parse::Request(req) => req,
+            let pending_requests = self.pending_requests.clone();
+            let new_requests = new_requests.map(move |item| match item {
+                Parse::Request(req) => {
+                    pending_requests.set(pending_requests.get() - 1);
+                    req
+                }
                 _ => unreachable!("requests stream got item"),
             });
 
-            let pool = self.pool.clone();
-
-            let new_requests = new_requests
-                .map(move |req| pool.spawn_fn(|| Ok(get_digest_and_request(req))))
-                .buffered(4);
-
-            let new_requests = self.rfp_filter.unique(new_requests);
-
             let mut new_items = new_items.map(|item| match item {
                 Parse::Item(item) => item,
                 _ => unreachable!("items stream got request"),
@@ -107,21 +592,30 @@ where
                 None => Box::new(new_items) as ItemStream<Self::Item>,
             };
 
-            {
-                let mut sheduler = self.sheduler.borrow_mut();
-                sheduler.shedule(Box::new(new_requests));
-            }
-
+            self.shedule_requests(new_requests);
             self.output.push(new_items);
         }
 
-        if let Async::Ready(Some(item)) = self.output.poll()? {
-            return Ok(Async::Ready(Some(item)));
+        while let Async::Ready(Some(item)) = self.output.poll()? {
+            self.pending_items.set(self.pending_items.get() - 1);
+            if !self.is_duplicate_item(&item) {
+                self.stats.item_scraped();
+                self.events.emit(|| Event::ItemScraped {
+                    item: item.to_string(),
+                });
+                return Ok(Async::Ready(Some(item)));
+            }
         }
 
-        let sheduler = self.sheduler.borrow();
+        let done = self.sheduler.borrow().is_done();
 
-        if sheduler.is_done() && self.parsing.is_empty() {
+        // buffered items are returned before the crawl ends, also on shutdown
+        if done && self.parsing.is_empty() && self.output.is_empty() {
+            // the sheduler wakes the task up when the new requests are chained
+            if self.idle() {
+                return Ok(Async::NotReady);
+            }
+            self.finish();
             Ok(Async::Ready(None))
         } else {
             Ok(Async::NotReady)
@@ -134,20 +628,26 @@ impl<SH> Crawler<SH>
 where
     SH: Sheduler,
 {
+    /// Stats of the last crawl, they are reset when a new crawl starts.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Events of every crawl of this crawler.
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
     pub fn crawl<S>(&self, mut spider: S) -> Crawl<S, SH>
     where
         S: Spider,
     {
         let pool = self.pool.clone();
-        let cloned_pool = self.pool.clone();
-        let start_stream = spider.start().flatten_stream();
-        let start_stream = filter_and_log_errors(start_stream, &self.logger)
-            .eos_on_error(&self.logger)
-            .map(move |req| cloned_pool.spawn_fn(|| Ok(get_digest_and_request(req))))
-            .buffered(4);
+        let opening = Some(spider.opened());
 
         let parsing = FuturesUnordered::new();
         let output = SelectAll::new();
+        self.sheduler.borrow_mut().open();
         let sheduler = self.sheduler.clone();
         let name = spider.name();
         let parse_settings = self.parse_settings.clone();
@@ -157,23 +657,57 @@ where
             None => None,
         };
 
-        let rfp_filter = RFPFilter::new(pool.clone(), logger.clone());
-        let start_stream: InternalRequestStream = Box::new(rfp_filter.unique(start_stream));
-
-        {
-            let mut borrowed = sheduler.borrow_mut();
-            borrowed.shedule(start_stream);
+        let stats = self.stats.clone();
+        stats.start();
+        let events = self.events.clone();
+        let rfp_filter =
+            RFPFilter::new(pool.clone(), stats.clone(), events.clone(), logger.clone());
+        let offsite_filter = OffsiteFilter::new(spider.allowed_domains(), logger.clone());
+        let offsite_filter = Rc::new(RefCell::new(offsite_filter));
+        let mut spider_headers = spider.default_headers();
+        if let Some(user_agent) = spider.user_agent() {
+            spider_headers.set_raw("User-Agent", user_agent.to_owned());
         }
+        self.default_headers.set_spider_headers(spider_headers);
+        let (shutdown, shutdown_requests) = ShutdownHandle::new();
+        let context_pool = match parse_settings {
+            ParseSettings::OnPool => Some(pool.clone()),
+            ParseSettings::SameThread => None,
+        };
+        let (context, fetch_requests) = CrawlContext::new(context_pool);
 
         Crawl {
             spider,
+            opening,
+            context,
+            fetch_requests,
+            fetches: HashMap::new(),
+            next_fetch_id: 0,
             sheduler,
             parsing,
             output,
             logger,
             pool,
             parse_settings,
+            request_buffer: self.request_buffer,
+            backpressure: self.backpressure.clone(),
+            pending_items: Rc::new(Cell::new(0)),
+            pending_requests: Rc::new(Cell::new(0)),
             rfp_filter,
+            offsite_filter,
+            item_filter: None,
+            spider_middlewares: SpiderMiddlewares::new(),
+            stats,
+            events,
+            close_conditions: self.close_conditions.clone(),
+            close_timer: None,
+            close_reason: None,
+            shutdown,
+            shutdown_requests,
+            shutdown_deadline: self.shutdown_deadline,
+            shutdown_timer: None,
+            forced: false,
+            handle: self.handle.clone(),
         }
     }
 }
@@ -182,15 +716,45 @@ pub struct CrawlerBuilder<SH> {
     sheduler: SH,
     logger: Option<Logger>,
     pool: Option<CpuPool>,
+    pool_size: Option<usize>,
     parse_settings: Option<ParseSettings>,
+    request_buffer: usize,
+    backpressure: Backpressure,
+    downloader_middlewares: Vec<Box<DownloaderMiddleware>>,
+    default_headers: Headers,
+    user_agents: Vec<String>,
+    handle: Option<Handle>,
+    client_config: ClientConfig,
+    proxy: Option<String>,
+    limits: DownloadLimits,
+    close_conditions: CloseConditions,
+    shutdown_deadline: Option<Duration>,
+    download_threads: Option<usize>,
+    stats: Option<Stats>,
 }
 
-#[derive(Clone)]
+/// Where responses are parsed.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ParseSettings {
+    /// Parse futures are spawned on the CPU pool.
     OnPool,
+    /// Parse futures run on the crawl thread, for cheap parsing.
     SameThread,
 }
 
+impl FromStr for ParseSettings {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "on_pool" => Ok(ParseSettings::OnPool),
+            "same_thread" => Ok(ParseSettings::SameThread),
+            _ => Err(format_err!("unknown parse mode {}, expected on_pool or same_thread", s)),
+        }
+    }
+}
+
 impl<SH> CrawlerBuilder<SH>
 where
     SH: Sheduler,
@@ -198,12 +762,46 @@ where
     pub fn new(sheduler: SH) -> Self {
         let logger = None;
         let pool = None;
+        let pool_size = None;
         let parse_settings = None;
+        let request_buffer = 4;
+        let backpressure = Backpressure::default();
+        let downloader_middlewares = Vec::new();
+        let mut default_headers = Headers::new();
+        default_headers.set_raw("User-Agent", DEFAULT_USER_AGENT);
+        default_headers.set_raw(
+            "Accept",
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+        );
+        default_headers.set_raw("Accept-Language", "en");
+        let user_agents = Vec::new();
+        let handle = None;
+        let client_config = ClientConfig::default();
+        let proxy = None;
+        let limits = DownloadLimits::default();
+        let close_conditions = CloseConditions::default();
+        let shutdown_deadline = None;
+        let download_threads = None;
+        let stats = None;
         Self {
             logger,
             sheduler,
             pool,
+            pool_size,
             parse_settings,
+            request_buffer,
+            backpressure,
+            downloader_middlewares,
+            default_headers,
+            user_agents,
+            handle,
+            client_config,
+            proxy,
+            limits,
+            close_conditions,
+            shutdown_deadline,
+            download_threads,
+            stats,
         }
     }
 
@@ -212,12 +810,303 @@ where
         self
     }
 
+    /// CPU pool for parsing and request fingerprints, by default one thread per CPU.
+    pub fn with_pool(mut self, pool: CpuPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Size of the CPU pool built by the crawler, ignored with `with_pool`.
+    /// `build` fails if it is 0.
+    pub fn with_pool_size(mut self, size: usize) -> Self {
+        self.pool_size = Some(size);
+        self
+    }
+
+    pub fn with_parse_settings(mut self, parse_settings: ParseSettings) -> Self {
+        self.parse_settings = Some(parse_settings);
+        self
+    }
+
+    /// Number of requests fingerprinted on the pool at once before sheduling, 4 by default.
+    /// `build` fails if it is 0.
+    pub fn with_request_buffer(mut self, size: usize) -> Self {
+        self.request_buffer = size;
+        self
+    }
+
+    /// Stops taking responses from the sheduler while `count` responses are being parsed,
+    /// see `Backpressure`.
+    pub fn with_max_pending_parses(mut self, count: usize) -> Self {
+        self.backpressure.parses = Some(count);
+        self
+    }
+
+    /// Pauses downloads and parses while `count` items wait for the consumer.
+    pub fn with_max_pending_items(mut self, count: usize) -> Self {
+        self.backpressure.items = Some(count);
+        self
+    }
+
+    /// Stops taking parse results while `count` requests from parses wait to be sheduled.
+    pub fn with_max_pending_requests(mut self, count: usize) -> Self {
+        self.backpressure.requests = Some(count);
+        self
+    }
+
+    /// Applies every value present in the settings.
+    pub fn with_settings(mut self, settings: &Settings) -> Self {
+        if let Some(size) = settings.pool_size {
+            self = self.with_pool_size(size);
+        }
+        if let Some(ref parse_settings) = settings.parse_mode {
+            self = self.with_parse_settings(parse_settings.clone());
+        }
+        if let Some(size) = settings.request_buffer {
+            self = self.with_request_buffer(size);
+        }
+        if let Some(count) = settings.max_pending_parses {
+            self = self.with_max_pending_parses(count);
+        }
+        if let Some(count) = settings.max_pending_items {
+            self = self.with_max_pending_items(count);
+        }
+        if let Some(count) = settings.max_pending_requests {
+            self = self.with_max_pending_requests(count);
+        }
+        if let Some(ref user_agent) = settings.user_agent {
+            self = self.with_user_agent(user_agent);
+        }
+        if let Some(ref proxy) = settings.proxy {
+            self = self.with_proxy(proxy);
+        }
+        if let Some(threads) = settings.download_threads {
+            self = self.with_download_threads(threads);
+        }
+        if let Some(size) = settings.max_response_size {
+            self = self.with_max_response_size(size);
+        }
+        if let Some(size) = settings.warn_response_size {
+            self = self.with_warn_response_size(size);
+        }
+        if let Some(timeout) = settings.connect_timeout {
+            self = self.with_connect_timeout(seconds(timeout));
+        }
+        if let Some(timeout) = settings.download_timeout {
+            self = self.with_download_timeout(seconds(timeout));
+        }
+        if let Some(count) = settings.close_on_items {
+            self = self.with_close_on_items(count);
+        }
+        if let Some(count) = settings.close_on_pages {
+            self = self.with_close_on_pages(count);
+        }
+        if let Some(count) = settings.close_on_errors {
+            self = self.with_close_on_errors(count);
+        }
+        if let Some(timeout) = settings.close_timeout {
+            self = self.with_close_timeout(seconds(timeout));
+        }
+        if let Some(deadline) = settings.shutdown_deadline {
+            self = self.with_shutdown_deadline(seconds(deadline));
+        }
+        self
+    }
+
+    /// Appends a middleware to the downloader chain, see `DownloaderMiddleware`
+    /// for the order in which hooks are called.
+    pub fn with_downloader_middleware<M>(mut self, middleware: M) -> Self
+    where
+        M: DownloaderMiddleware + 'static,
+    {
+        self.downloader_middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /// Sets a header on every request which doesn't have it, spider and request headers win.
+    /// By default `User-Agent`, `Accept` and `Accept-Language` are set.
+    pub fn with_default_header<K, V>(mut self, name: K, value: V) -> Self
+    where
+        K: Into<Cow<'static, str>>,
+        V: Into<Raw>,
+    {
+        self.default_headers.set_raw(name, value);
+        self
+    }
+
+    pub fn with_user_agent(self, user_agent: &str) -> Self {
+        self.with_default_header("User-Agent", user_agent.to_owned())
+    }
+
+    /// Rotates user agents of requests without one, takes precedence over `with_user_agent`.
+    pub fn with_user_agent_pool(mut self, user_agents: Vec<String>) -> Self {
+        self.user_agents = user_agents;
+        self
+    }
+
+    /// Handle of the reactor the crawl runs on, needed to build clients for proxies.
+    pub fn with_handle(mut self, handle: Handle) -> Self {
+        self.handle = Some(handle);
+        self
+    }
+
+    /// Configuration of the clients the crawler builds for proxies and download workers,
+    /// should be the one the sheduler client was built with.
+    pub fn with_client_config(mut self, config: ClientConfig) -> Self {
+        self.client_config = config;
+        self
+    }
+
+    /// Routes every request through the proxy unless the request sets its own
+    /// with `PROXY_META_KEY` meta, `http`, `https` and `socks5` proxies are supported.
+    /// Responses through a SOCKS proxy are not decoded from gzip and the redirects of
+    /// `ClientConfig` are not followed, see `RedirectMiddleware`. Requires `with_handle`.
+    pub fn with_proxy(mut self, proxy: &str) -> Self {
+        self.proxy = Some(proxy.to_owned());
+        self
+    }
+
+    /// Downloads of larger responses are aborted with `DownloadError::TooLarge`, 0 means
+    /// no limit. Can be overridden per request with `MAX_SIZE_META_KEY` meta.
+    pub fn with_max_response_size(mut self, max_size: usize) -> Self {
+        self.limits.max_size = if max_size == 0 { None } else { Some(max_size) };
+        self
+    }
+
+    /// Logs a warning for larger responses, 0 means no warning. Can be overridden
+    /// per request with `WARN_SIZE_META_KEY` meta.
+    pub fn with_warn_response_size(mut self, warn_size: usize) -> Self {
+        self.limits.warn_size = if warn_size == 0 { None } else { Some(warn_size) };
+        self
+    }
+
+    /// Maximum time until response headers are received, can be overridden per request
+    /// with `CONNECT_TIMEOUT_META_KEY` meta. Requires `with_handle`.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Maximum time of a whole download, can be overridden per request
+    /// with `DOWNLOAD_TIMEOUT_META_KEY` meta. Requires `with_handle`.
+    pub fn with_download_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.download_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes the crawl after `count` items, see `CloseConditions`.
+    pub fn with_close_on_items(mut self, count: u64) -> Self {
+        self.close_conditions.items = Some(count);
+        self
+    }
+
+    /// Closes the crawl after `count` downloaded pages.
+    pub fn with_close_on_pages(mut self, count: u64) -> Self {
+        self.close_conditions.pages = Some(count);
+        self
+    }
+
+    /// Closes the crawl after `count` failed downloads.
+    pub fn with_close_on_errors(mut self, count: u64) -> Self {
+        self.close_conditions.errors = Some(count);
+        self
+    }
+
+    /// Closes the crawl when it runs longer than `timeout`. Requires `with_handle`.
+    pub fn with_close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_conditions.timeout = Some(timeout);
+        self
+    }
+
+    /// Forces the shutdown when downloads and parses in progress don't finish
+    /// in `deadline` after a graceful shutdown was requested. Requires `with_handle`.
+    pub fn with_shutdown_deadline(mut self, deadline: Duration) -> Self {
+        self.shutdown_deadline = Some(deadline);
+        self
+    }
+
+    /// Downloads on `threads` worker threads with their own reactors,
+    /// see `DownloadWorkers`. Proxies and timeouts don't need `with_handle` then.
+    pub fn with_download_threads(mut self, threads: usize) -> Self {
+        self.download_threads = Some(threads);
+        self
+    }
+
+    /// Counts into `stats` instead of new stats, e.g. to share them with a middleware.
+    pub fn with_stats(mut self, stats: Stats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Limits configured so far, e.g. for a middleware downloading on its own.
+    pub fn download_limits(&self) -> DownloadLimits {
+        self.limits.clone()
+    }
+
     pub fn build(self) -> Result<Crawler<SH>, Error> {
+        if self.pool_size == Some(0) {
+            bail!("pool size must be at least 1");
+        }
+        if self.request_buffer == 0 {
+            bail!("request buffer must be at least 1");
+        }
+        let bounds = [
+            self.backpressure.parses,
+            self.backpressure.items,
+            self.backpressure.requests,
+        ];
+        if bounds.contains(&Some(0)) {
+            bail!("max pending parses, items and requests must be at least 1");
+        }
+        let threaded = self.download_threads.is_some();
+        if self.proxy.is_some() && self.handle.is_none() && !threaded {
+            bail!("proxy requires a reactor handle, see CrawlerBuilder::with_handle");
+        }
+        if self.limits.has_timeouts() && self.handle.is_none() && !threaded {
+            bail!("timeouts require a reactor handle, see CrawlerBuilder::with_handle");
+        }
+        if self.close_conditions.timeout.is_some() && self.handle.is_none() {
+            bail!("close timeout requires a reactor handle, see CrawlerBuilder::with_handle");
+        }
+        if self.shutdown_deadline.is_some() && self.handle.is_none() {
+            bail!("shutdown deadline requires a reactor handle, see CrawlerBuilder::with_handle");
+        }
+        let close_conditions = self.close_conditions;
+        let shutdown_deadline = self.shutdown_deadline;
         let logger = self.logger;
-        let sheduler = Rc::new(RefCell::new(self.sheduler));
-        let pool = match self.pool {
-            Some(pool) => pool,
-            None => CpuPool::new_num_cpus(),
+        let mut sheduler = self.sheduler;
+        let default_headers = DefaultHeaders::new(self.default_headers, self.user_agents);
+        let handle = self.handle;
+        let workers = match self.download_threads {
+            Some(threads) => Some(DownloadWorkers::new(
+                threads,
+                self.client_config.clone(),
+                self.proxy.clone(),
+                logger.clone(),
+            )?),
+            None => None,
+        };
+        let proxies = ProxyClients::new(handle.clone(), self.proxy, self.client_config);
+        let mut downloader = Downloader::new(self.downloader_middlewares, default_headers)
+            .with_proxies(proxies)
+            .with_limits(self.limits, handle.clone());
+        if let Some(workers) = workers {
+            downloader = downloader.with_workers(workers);
+        }
+        if let Some(ref logger) = logger {
+            downloader = downloader.with_logger(logger.clone());
+        }
+        let default_headers = downloader.default_headers();
+        sheduler.set_downloader(downloader);
+        let stats = self.stats.unwrap_or_default();
+        sheduler.set_stats(stats.clone());
+        let events = EventBus::new();
+        sheduler.set_events(events.clone());
+        let sheduler = Rc::new(RefCell::new(sheduler));
+        let pool = match (self.pool, self.pool_size) {
+            (Some(pool), _) => pool,
+            (None, Some(size)) => CpuPool::new(size),
+            (None, None) => CpuPool::new_num_cpus(),
         };
 
         let parse_settings = match self.parse_settings {
@@ -228,8 +1117,662 @@ where
         Ok(Crawler {
             logger,
             sheduler,
+            default_headers,
             pool,
             parse_settings,
+            request_buffer: self.request_buffer,
+            backpressure: self.backpressure,
+            stats,
+            events,
+            close_conditions,
+            shutdown_deadline,
+            handle,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{lazy, ok};
+    use reqwest::unstable::async::Client;
+    use reqwest::Method;
+    use spider::{Parse, ParseStream, RequestStream};
+    use stats::StatsSnapshot;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use test_server::{response, stalling_server, TestServer};
+    use tokio_core::reactor::Core;
+    use url::Url;
+
+    /// Starts with the urls and scrapes the url of every page.
+    struct PagesSpider {
+        urls: Vec<Url>,
+    }
+
+    impl Spider for PagesSpider {
+        type Item = String;
+
+        fn name(&self) -> &'static str {
+            "pages"
+        }
+
+        fn start(&mut self, _: &CrawlContext) -> Box<Future<Item = RequestStream, Error = Error>> {
+            let requests: Vec<_> = self.urls
+                .iter()
+                .map(|url| Ok(Request::new(Method::Get, url.clone())))
+                .collect();
+            Box::new(ok(Box::new(iter_ok(requests)) as RequestStream))
+        }
+
+        fn parse(
+            &mut self,
+            response: Response,
+            _: &CrawlContext,
+        ) -> Box<Future<Item = ParseStream<String>, Error = Error> + Send> {
+            let item = Ok(Parse::Item(response.url().to_string()));
+            Box::new(ok(Box::new(iter_ok(vec![item])) as ParseStream<String>))
+        }
+    }
+
+    /// Fetches the index in `start`, it lists the paths of the pages to crawl.
+    struct IndexSpider {
+        index: Url,
+    }
+
+    impl Spider for IndexSpider {
+        type Item = String;
+
+        fn name(&self) -> &'static str {
+            "index"
+        }
+
+        fn start(&mut self, context: &CrawlContext) -> Box<Future<Item = RequestStream, Error = Error>> {
+            let index = self.index.clone();
+            let fut = context
+                .fetch(Request::new(Method::Get, index.clone()))
+                .map(move |response| {
+                    let requests: Vec<_> = response
+                        .text()
+                        .lines()
+                        .map(|path| Ok(Request::new(Method::Get, index.join(path)?)))
+                        .collect();
+                    Box::new(iter_ok(requests)) as RequestStream
+                });
+            Box::new(fut)
+        }
+
+        fn parse(
+            &mut self,
+            response: Response,
+            _: &CrawlContext,
+        ) -> Box<Future<Item = ParseStream<String>, Error = Error> + Send> {
+            let item = Ok(Parse::Item(response.url().path().to_owned()));
+            Box::new(ok(Box::new(iter_ok(vec![item])) as ParseStream<String>))
+        }
+    }
+
+    #[test]
+    fn start_fetches_and_then_yields_requests() {
+        let server = TestServer::start(vec![
+            ("/index", response("200 OK", &[], "/a\n/b")),
+            ("/a", response("200 OK", &[], "a")),
+            ("/b", response("200 OK", &[], "b")),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let crawler = CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1))
+            .with_handle(core.handle())
+            .with_close_timeout(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        let spider = IndexSpider {
+            index: server.url("/index"),
+        };
+        let items = Rc::new(RefCell::new(Vec::new()));
+        let collected = items.clone();
+        let crawl = crawler.crawl(spider).run(move |item| {
+            collected.borrow_mut().push(item);
+            Ok(())
+        });
+        assert_eq!(core.run(crawl).unwrap(), CloseReason::Finished);
+        let mut items = items.borrow().clone();
+        items.sort();
+        assert_eq!(items, vec!["/a", "/b"]);
+    }
+
+    #[test]
+    fn duplicate_items_are_dropped_and_counted() {
+        let server = TestServer::start(vec![
+            ("/a?page=1", response("200 OK", &[], "a")),
+            ("/a?page=2", response("200 OK", &[], "a")),
+            ("/b", response("200 OK", &[], "b")),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let crawler = CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1))
+            .with_parse_settings(ParseSettings::SameThread)
+            .build()
+            .unwrap();
+        let spider = PagesSpider {
+            urls: vec![
+                server.url("/a?page=1"),
+                server.url("/a?page=2"),
+                server.url("/b"),
+            ],
+        };
+        // the query is ignored, so both pages of /a are the same item
+        let filter = ItemFilter::in_memory(|item: &String| {
+            Url::parse(item).unwrap().path().to_owned()
+        });
+        let crawl = crawler.crawl(spider).with_item_filter(filter);
+        let stats = crawl.stats().clone();
+        let items = Rc::new(RefCell::new(Vec::new()));
+        let collected = items.clone();
+        let crawl = crawl.run(move |item| {
+            collected.borrow_mut().push(item);
+            Ok(())
+        });
+        assert_eq!(core.run(crawl).unwrap(), CloseReason::Finished);
+        let paths: Vec<_> = items
+            .borrow()
+            .iter()
+            .map(|item| Url::parse(item).unwrap().path().to_owned())
+            .collect();
+        assert_eq!(paths.len(), 2);
+        assert!(paths.contains(&"/a".to_owned()));
+        assert!(paths.contains(&"/b".to_owned()));
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.items, 2);
+        assert_eq!(snapshot.dropped.get(DROP_DUPLICATE_ITEM), Some(&1));
+    }
+
+    /// Skips parsing of responses to the path.
+    struct SkipPath(&'static str);
+
+    impl SpiderMiddleware<String> for SkipPath {
+        fn name(&self) -> &'static str {
+            "SkipPath"
+        }
+
+        fn process_input(&mut self, response: &Response) -> Result<(), Error> {
+            if response.url().path() == self.0 {
+                bail!("skipped {}", self.0);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn responses_rejected_by_process_input_are_not_parsed() {
+        let server = TestServer::start(vec![
+            ("/a", response("200 OK", &[], "a")),
+            ("/b", response("200 OK", &[], "b")),
+            ("/c", response("200 OK", &[], "c")),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let crawler = CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1))
+            .with_parse_settings(ParseSettings::SameThread)
+            .build()
+            .unwrap();
+        let spider = PagesSpider {
+            urls: vec![server.url("/a"), server.url("/b"), server.url("/c")],
+        };
+        let crawl = crawler.crawl(spider).with_spider_middleware(SkipPath("/b"));
+        let stats = crawl.stats().clone();
+        let items = Rc::new(RefCell::new(Vec::new()));
+        let collected = items.clone();
+        let crawl = crawl.run(move |item| {
+            collected.borrow_mut().push(item);
+            Ok(())
+        });
+        assert_eq!(core.run(crawl).unwrap(), CloseReason::Finished);
+        let mut items = items.borrow().clone();
+        items.sort();
+        assert_eq!(items, vec![server.url("/a").to_string(), server.url("/c").to_string()]);
+        let dropped = stats.snapshot().dropped;
+        assert_eq!(dropped.get(DROP_SPIDER_MIDDLEWARE), Some(&1));
+    }
+
+    /// Only allows pages on 127.0.0.1.
+    struct LocalPagesSpider(PagesSpider);
+
+    impl Spider for LocalPagesSpider {
+        type Item = String;
+
+        fn name(&self) -> &'static str {
+            "local pages"
+        }
+
+        fn allowed_domains(&self) -> &[&'static str] {
+            &["127.0.0.1"]
+        }
+
+        fn start(&mut self, context: &CrawlContext) -> Box<Future<Item = RequestStream, Error = Error>> {
+            self.0.start(context)
+        }
+
+        fn parse(
+            &mut self,
+            response: Response,
+            context: &CrawlContext,
+        ) -> Box<Future<Item = ParseStream<String>, Error = Error> + Send> {
+            self.0.parse(response, context)
+        }
+    }
+
+    #[test]
+    fn offsite_requests_are_dropped_and_counted() {
+        let local = TestServer::start(vec![("/a", response("200 OK", &[], "a"))]);
+        let offsite = TestServer::start_on("127.0.0.2", vec![("/b", response("200 OK", &[], "b"))]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let crawler = CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1))
+            .with_parse_settings(ParseSettings::SameThread)
+            .build()
+            .unwrap();
+        let spider = LocalPagesSpider(PagesSpider {
+            urls: vec![local.url("/a"), offsite.url("/b"), offsite.url("/c")],
+        });
+        let crawl = crawler.crawl(spider);
+        let stats = crawl.stats().clone();
+        let items = Rc::new(RefCell::new(Vec::new()));
+        let collected = items.clone();
+        let crawl = crawl.run(move |item| {
+            collected.borrow_mut().push(item);
+            Ok(())
+        });
+        assert_eq!(core.run(crawl).unwrap(), CloseReason::Finished);
+        assert_eq!(*items.borrow(), vec![local.url("/a").to_string()]);
+        assert!(offsite.requests().is_empty());
+        assert_eq!(stats.snapshot().dropped.get(DROP_OFFSITE), Some(&2));
+    }
+
+    #[test]
+    fn requests_queued_when_a_crawl_closes_are_not_downloaded_later() {
+        let ok = |body| response("200 OK", &[], body);
+        let server = TestServer::start(vec![
+            ("/a", ok("a")),
+            ("/b", ok("b")),
+            ("/c", ok("c")),
+            ("/d", ok("d")),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let crawler = CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1))
+            .with_parse_settings(ParseSettings::SameThread)
+            .with_close_on_items(1)
+            .build()
+            .unwrap();
+        let crawl = |paths: &[&str]| {
+            let urls = paths.iter().map(|path| server.url(path)).collect();
+            let items = Rc::new(RefCell::new(Vec::new()));
+            let collected = items.clone();
+            let crawl = crawler.crawl(PagesSpider { urls }).run(move |item| {
+                collected.borrow_mut().push(item);
+                Ok(())
+            });
+            (crawl, items)
+        };
+
+        let (first, _) = crawl(&["/a", "/b", "/c"]);
+        assert_eq!(core.run(first).unwrap(), CloseReason::ItemCount(1));
+        let (second, items) = crawl(&["/d"]);
+        assert_eq!(core.run(second).unwrap(), CloseReason::ItemCount(1));
+        assert_eq!(*items.borrow(), vec![server.url("/d").to_string()]);
+        assert!(!server.requests().contains(&"/c".to_owned()));
+    }
+
+    #[test]
+    fn graceful_shutdown_stops_sheduling() {
+        let ok = |body| response("200 OK", &[], body);
+        let server = TestServer::start(vec![("/a", ok("a")), ("/b", ok("b")), ("/c", ok("c"))]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let crawler = CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1))
+            .with_parse_settings(ParseSettings::SameThread)
+            .build()
+            .unwrap();
+        let spider = PagesSpider {
+            urls: vec![server.url("/a"), server.url("/b"), server.url("/c")],
+        };
+        let crawl = crawler.crawl(spider);
+        let shutdown = crawl.shutdown_handle();
+        let crawl = crawl.run(move |_| {
+            shutdown.shutdown();
+            Ok(())
+        });
+        assert_eq!(core.run(crawl).unwrap(), CloseReason::Shutdown);
+        assert!(!server.requests().contains(&"/c".to_owned()));
+    }
+
+    /// Runs a crawl of a page which is never answered, `shutdown` is called after 50ms.
+    fn shutdown_stalled<F>(deadline: Option<Duration>, shutdown: F) -> (CloseReason, Duration)
+    where
+        F: FnOnce(&ShutdownHandle) + 'static,
+    {
+        let stalled = stalling_server("");
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let mut builder = CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1))
+            .with_parse_settings(ParseSettings::SameThread)
+            .with_handle(core.handle());
+        if let Some(deadline) = deadline {
+            builder = builder.with_shutdown_deadline(deadline);
+        }
+        let crawler = builder.build().unwrap();
+        let url = format!("http://{}/", stalled).parse().unwrap();
+        let crawl = crawler.crawl(PagesSpider { urls: vec![url] });
+        let handle = crawl.shutdown_handle();
+        let timer = Timeout::new(Duration::from_millis(50), &core.handle()).unwrap();
+        core.handle().spawn(timer.then(move |_| {
+            shutdown(&handle);
+            Ok(())
+        }));
+        let started = Instant::now();
+        let reason = core.run(crawl.run(|_| Ok(()))).unwrap();
+        (reason, started.elapsed())
+    }
+
+    #[test]
+    fn second_shutdown_forces_it() {
+        let (reason, elapsed) = shutdown_stalled(None, |handle| {
+            handle.shutdown();
+            handle.shutdown();
+        });
+        assert_eq!(reason, CloseReason::ShutdownForced);
+        assert!(elapsed < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn shutdown_is_forced_after_the_deadline() {
+        let deadline = Some(Duration::from_millis(200));
+        let (reason, elapsed) = shutdown_stalled(deadline, |handle| handle.shutdown());
+        assert_eq!(reason, CloseReason::ShutdownForced);
+        assert!(elapsed >= Duration::from_millis(250));
+        assert!(elapsed < Duration::from_secs(5));
+    }
+
+    /// Remembers the arguments of `Spider::closed`.
+    struct ClosingSpider {
+        pages: PagesSpider,
+        closed: Rc<RefCell<Option<(CloseReason, StatsSnapshot)>>>,
+    }
+
+    impl Spider for ClosingSpider {
+        type Item = String;
+
+        fn name(&self) -> &'static str {
+            "closing"
+        }
+
+        fn closed(&mut self, reason: &CloseReason, stats: &StatsSnapshot) {
+            *self.closed.borrow_mut() = Some((reason.clone(), stats.clone()));
+        }
+
+        fn start(&mut self, context: &CrawlContext) -> Box<Future<Item = RequestStream, Error = Error>> {
+            self.pages.start(context)
+        }
+
+        fn parse(
+            &mut self,
+            response: Response,
+            context: &CrawlContext,
+        ) -> Box<Future<Item = ParseStream<String>, Error = Error> + Send> {
+            self.pages.parse(response, context)
+        }
+    }
+
+    /// Runs a crawl of the urls, returns the reason it resolved to and what `closed` got.
+    fn close<F>(configure: F, urls: Vec<Url>) -> (CloseReason, CloseReason, StatsSnapshot)
+    where
+        F: FnOnce(CrawlerBuilder<GlobalLimitedSheduler>) -> CrawlerBuilder<GlobalLimitedSheduler>,
+    {
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let builder = CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1))
+            .with_parse_settings(ParseSettings::SameThread);
+        let crawler = configure(builder).build().unwrap();
+        let closed = Rc::new(RefCell::new(None));
+        let spider = ClosingSpider {
+            pages: PagesSpider { urls },
+            closed: closed.clone(),
+        };
+        let reason = core.run(crawler.crawl(spider).run(|_| Ok(()))).unwrap();
+        let (closed_reason, stats) = closed.borrow_mut().take().expect("closed wasn't called");
+        (reason, closed_reason, stats)
+    }
+
+    #[test]
+    fn spider_closed_gets_close_reason_and_stats() {
+        let ok = |body| response("200 OK", &[], body);
+        let server = TestServer::start(vec![("/a", ok("a")), ("/b", ok("b"))]);
+        let urls = || vec![server.url("/a"), server.url("/b")];
+
+        let (reason, closed, stats) = close(|builder| builder, urls());
+        assert_eq!((reason, closed), (CloseReason::Finished, CloseReason::Finished));
+        assert_eq!((stats.items, stats.pages(), stats.requests_sheduled), (2, 2, 2));
+
+        let (reason, closed, stats) = close(|builder| builder.with_close_on_pages(1), urls());
+        assert_eq!((reason, closed), (CloseReason::PageCount(1), CloseReason::PageCount(1)));
+        assert_eq!(stats.pages(), 1);
+
+        let refused = vec!["http://127.0.0.1:1/".parse().unwrap()];
+        let (reason, closed, stats) = close(|builder| builder.with_close_on_errors(1), refused);
+        assert_eq!((reason, closed), (CloseReason::ErrorCount(1), CloseReason::ErrorCount(1)));
+        assert_eq!((stats.errors, stats.pages()), (1, 0));
+    }
+
+    /// Logs its hooks next to the names of the crawl events.
+    struct HookSpider {
+        pages: PagesSpider,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Spider for HookSpider {
+        type Item = String;
+
+        fn name(&self) -> &'static str {
+            "hooks"
+        }
+
+        fn opened(&mut self) -> Box<Future<Item = (), Error = Error>> {
+            let log = self.log.clone();
+            Box::new(lazy(move || {
+                log.lock().unwrap().push("opened".to_owned());
+                Ok(())
+            }))
+        }
+
+        fn closed(&mut self, _reason: &CloseReason, _stats: &StatsSnapshot) {
+            self.log.lock().unwrap().push("closed".to_owned());
+        }
+
+        fn start(&mut self, context: &CrawlContext) -> Box<Future<Item = RequestStream, Error = Error>> {
+            self.pages.start(context)
+        }
+
+        fn parse(
+            &mut self,
+            response: Response,
+            context: &CrawlContext,
+        ) -> Box<Future<Item = ParseStream<String>, Error = Error> + Send> {
+            self.pages.parse(response, context)
+        }
+    }
+
+    #[test]
+    fn events_are_emitted_in_crawl_order() {
+        let server = TestServer::start(vec![("/a", response("200 OK", &[], "a"))]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let crawler = CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1))
+            .with_parse_settings(ParseSettings::SameThread)
+            .build()
+            .unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let events = log.clone();
+        crawler.events().subscribe(move |event| {
+            let name = format!("{:?}", event);
+            let name = name.split(' ').next().unwrap().to_owned();
+            events.lock().unwrap().push(name);
+        });
+        let spider = HookSpider {
+            pages: PagesSpider {
+                urls: vec![server.url("/a")],
+            },
+            log: log.clone(),
+        };
+        core.run(crawler.crawl(spider).run(|_| Ok(()))).unwrap();
+
+        let log = log.lock().unwrap();
+        let expected = [
+            "opened",
+            "SpiderOpened",
+            "RequestSheduled",
+            "ResponseReceived",
+            "ItemScraped",
+            "SpiderIdle",
+            "closed",
+            "SpiderClosed",
+        ];
+        assert_eq!(*log, expected);
+    }
+
+    /// Crawls the next batch of urls whenever it is idle.
+    struct BatchSpider {
+        pages: PagesSpider,
+        batches: Vec<Vec<Url>>,
+        idle_calls: Rc<Cell<usize>>,
+    }
+
+    impl Spider for BatchSpider {
+        type Item = String;
+
+        fn name(&self) -> &'static str {
+            "batches"
+        }
+
+        fn idle(&mut self) -> Option<RequestStream> {
+            self.idle_calls.set(self.idle_calls.get() + 1);
+            self.batches.pop().map(|urls| {
+                let requests: Vec<_> = urls.into_iter()
+                    .map(|url| Ok(Request::new(Method::Get, url)))
+                    .collect();
+                Box::new(iter_ok(requests)) as RequestStream
+            })
+        }
+
+        fn start(&mut self, context: &CrawlContext) -> Box<Future<Item = RequestStream, Error = Error>> {
+            self.pages.start(context)
+        }
+
+        fn parse(
+            &mut self,
+            response: Response,
+            context: &CrawlContext,
+        ) -> Box<Future<Item = ParseStream<String>, Error = Error> + Send> {
+            self.pages.parse(response, context)
+        }
+    }
+
+    #[test]
+    fn idle_spider_shedules_more_requests() {
+        let ok = |body| response("200 OK", &[], body);
+        let server = TestServer::start(vec![("/a", ok("a")), ("/b", ok("b"))]);
+        let crawl = |close_on_items: Option<u64>| {
+            let mut core = Core::new().unwrap();
+            let client = Client::new(&core.handle());
+            let mut builder = CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1))
+                .with_parse_settings(ParseSettings::SameThread);
+            if let Some(count) = close_on_items {
+                builder = builder.with_close_on_items(count);
+            }
+            let crawler = builder.build().unwrap();
+            let idle_calls = Rc::new(Cell::new(0));
+            let spider = BatchSpider {
+                pages: PagesSpider {
+                    urls: vec![server.url("/a")],
+                },
+                batches: vec![vec![server.url("/b")]],
+                idle_calls: idle_calls.clone(),
+            };
+            let items = Rc::new(RefCell::new(Vec::new()));
+            let collected = items.clone();
+            let crawl = crawler.crawl(spider).run(move |item| {
+                collected.borrow_mut().push(item);
+                Ok(())
+            });
+            let reason = core.run(crawl).unwrap();
+            let items = items.borrow().clone();
+            (reason, items, idle_calls.get())
+        };
+
+        // the first idle call returns the batch, the second one ends the crawl
+        let (reason, items, idle_calls) = crawl(None);
+        assert_eq!(reason, CloseReason::Finished);
+        assert_eq!(items, vec![server.url("/a").to_string(), server.url("/b").to_string()]);
+        assert_eq!(idle_calls, 2);
+
+        // a closing crawl doesn't ask for more requests
+        let (reason, items, idle_calls) = crawl(Some(1));
+        assert_eq!(reason, CloseReason::ItemCount(1));
+        assert_eq!(items, vec![server.url("/a").to_string()]);
+        assert_eq!(idle_calls, 0);
+    }
+
+    #[test]
+    fn backpressure_bounds_parses() {
+        let backpressure = Backpressure {
+            parses: Some(2),
+            items: Some(2),
+            requests: Some(2),
+        };
+        assert_eq!(backpressure.flow(0, 0, 0), (true, true));
+        assert_eq!(backpressure.flow(2, 0, 0), (false, false));
+        assert_eq!(backpressure.flow(0, 2, 0), (false, true));
+        assert_eq!(backpressure.flow(0, 0, 2), (true, false));
+        // parses in progress are finished to get requests sheduled again
+        assert_eq!(backpressure.flow(0, 2, 2), (false, true));
+    }
+
+    #[test]
+    fn zero_sizes_are_rejected() {
+        let core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let builder = || CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1));
+        assert!(builder().with_pool_size(0).build().is_err());
+        assert!(builder().with_request_buffer(0).build().is_err());
+        assert!(builder().with_max_pending_items(0).build().is_err());
+        assert!(builder().with_pool_size(1).with_request_buffer(1).build().is_ok());
+    }
+
+    #[test]
+    fn run_resolves_to_close_reason_and_next_crawl_reopens_sheduler() {
+        let server = TestServer::start(vec![
+            ("/a", response("200 OK", &[], "a")),
+            ("/b", response("200 OK", &[], "b")),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let crawler = CrawlerBuilder::new(GlobalLimitedSheduler::new(&client, 1))
+            .with_parse_settings(ParseSettings::SameThread)
+            .with_close_on_items(1)
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            let spider = PagesSpider {
+                urls: vec![server.url("/a"), server.url("/b")],
+            };
+            let items = Rc::new(Cell::new(0));
+            let counted = items.clone();
+            let crawl = crawler.crawl(spider).run(move |_| {
+                counted.set(counted.get() + 1);
+                Ok(())
+            });
+            assert_eq!(core.run(crawl).unwrap(), CloseReason::ItemCount(1));
+            assert!(items.get() >= 1);
+        }
+    }
+}
@@ -0,0 +1,823 @@
+use bytes::Bytes;
+use client::HttpClient;
+use failure::Error;
+use futures::future::{err, ok};
+use futures::{Future, Stream};
+use proxy::ProxyClients;
+use request::Request;
+use response::Response;
+use reqwest::header::{ContentLength, Headers};
+use reqwest::unstable::async::Client;
+use reqwest::StatusCode;
+use settings::seconds;
+use slog::Logger;
+use socks::socks_request;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
+use url::Url;
+use workers::DownloadWorkers;
+
+/// Meta key overriding the maximum response size in bytes for a request, 0 means no limit.
+pub const MAX_SIZE_META_KEY: &str = "download_maxsize";
+/// Meta key overriding the response size in bytes after which a warning is logged,
+/// 0 means no warning.
+pub const WARN_SIZE_META_KEY: &str = "download_warnsize";
+/// Meta key overriding the connect timeout for a request, in seconds.
+pub const CONNECT_TIMEOUT_META_KEY: &str = "connect_timeout";
+/// Meta key overriding the download timeout for a request, in seconds.
+pub const DOWNLOAD_TIMEOUT_META_KEY: &str = "download_timeout";
+
+#[derive(Debug, Fail)]
+pub enum DownloadError {
+    #[fail(display = "response is larger than {} bytes", max_size)]
+    TooLarge { max_size: usize },
+    #[fail(display = "no response in {:?}", _0)]
+    ConnectTimeout(Duration),
+    #[fail(display = "download was not finished in {:?}", _0)]
+    DownloadTimeout(Duration),
+}
+
+/// Size limits and timeouts of downloads.
+///
+/// The connect timeout covers everything until response headers are received,
+/// the client doesn't expose the connection phase separately.
+/// The download timeout covers the whole download including the body.
+#[derive(Clone, Debug, Default)]
+pub struct DownloadLimits {
+    pub(crate) max_size: Option<usize>,
+    pub(crate) warn_size: Option<usize>,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) download_timeout: Option<Duration>,
+}
+
+impl DownloadLimits {
+    pub fn has_timeouts(&self) -> bool {
+        self.connect_timeout.is_some() || self.download_timeout.is_some()
+    }
+
+    /// Limits of the request, taking its meta overrides into account.
+    /// An override which can't be parsed is ignored with a warning.
+    fn for_request(&self, request: &Request, logger: &Option<Logger>) -> DownloadLimits {
+        let meta = request.meta();
+        let invalid = |key: &str, value: &str| {
+            if let Some(ref logger) = *logger {
+                warn!(logger, "invalid download limit in meta, using the crawler default";
+                      "request" => %request, "key" => key, "value" => value);
+            }
+        };
+        let size = |key: &str, default: Option<usize>| match meta.get(key) {
+            Some(value) => match value.parse() {
+                Ok(0) => None,
+                Ok(size) => Some(size),
+                Err(_) => {
+                    invalid(key, value);
+                    default
+                }
+            },
+            None => default,
+        };
+        let timeout = |key: &str, default: Option<Duration>| match meta.get(key) {
+            Some(value) => match value.parse() {
+                Ok(timeout) => Some(seconds(timeout)),
+                Err(_) => {
+                    invalid(key, value);
+                    default
+                }
+            },
+            None => default,
+        };
+        DownloadLimits {
+            max_size: size(MAX_SIZE_META_KEY, self.max_size),
+            warn_size: size(WARN_SIZE_META_KEY, self.warn_size),
+            connect_timeout: timeout(CONNECT_TIMEOUT_META_KEY, self.connect_timeout),
+            download_timeout: timeout(DOWNLOAD_TIMEOUT_META_KEY, self.download_timeout),
+        }
+    }
+}
+
+/// What to do with a request after `DownloaderMiddleware::process_request`.
+pub enum RequestAction {
+    /// Pass the (possibly modified) request to the next middleware and then to the client.
+    Continue(Request),
+    /// Don't download anything, use this response instead.
+    Respond(Response),
+    /// Forget about the request.
+    Drop,
+    /// Decide later, e.g. after fetching something the decision depends on.
+    Pending(Box<Future<Item = RequestAction, Error = Error>>),
+}
+
+/// What to do with a response after `DownloaderMiddleware::process_response`.
+pub enum ResponseAction {
+    /// Pass the (possibly modified or replaced) response to the next middleware.
+    Continue(Response),
+    /// Discard the response and shedule a new request instead.
+    Reschedule(Request),
+    /// Discard the response.
+    Drop,
+}
+
+/// What to do with a failed download after `DownloaderMiddleware::process_error`.
+pub enum ErrorAction {
+    /// Pass the error to the next middleware.
+    Fail(Error),
+    /// Recover from the error with this response.
+    Respond(Response),
+    /// Shedule a new request instead.
+    Reschedule(Request),
+    /// Forget about the request.
+    Drop,
+}
+
+/// Hooks around request execution.
+///
+/// `process_request` hooks are called in the order the middlewares were added
+/// to `CrawlerBuilder`, `process_response` and `process_error` hooks in reverse order.
+pub trait DownloaderMiddleware {
+    fn name(&self) -> &'static str;
+
+    fn process_request(&mut self, request: Request) -> RequestAction {
+        RequestAction::Continue(request)
+    }
+
+    fn process_response(&mut self, response: Response) -> ResponseAction {
+        ResponseAction::Continue(response)
+    }
+
+    fn process_error(&mut self, _request: &Request, error: Error) -> ErrorAction {
+        ErrorAction::Fail(error)
+    }
+}
+
+/// Outcome of a single download.
+pub enum Downloaded {
+    /// The middlewares passed the request, it is ready for `Downloader::download`.
+    Ready(Request),
+    Response(Response),
+    Reschedule(Request),
+    Dropped,
+}
+
+type Middlewares = Rc<RefCell<Vec<Box<DownloaderMiddleware>>>>;
+
+pub(crate) type RawResponse = (Url, StatusCode, Headers, Bytes);
+
+pub type DownloadFuture = Box<Future<Item = Downloaded, Error = Error>>;
+
+/// Headers set on every request which doesn't have them yet, applied before the
+/// middlewares so they see the request as it is sent.
+///
+/// Spider headers win over the user agent pool, which wins over crawler headers.
+pub struct DefaultHeaders {
+    spider_headers: RefCell<Headers>,
+    headers: Headers,
+    user_agents: Vec<String>,
+    next_user_agent: Cell<usize>,
+}
+
+impl Default for DefaultHeaders {
+    fn default() -> Self {
+        DefaultHeaders::new(Headers::new(), Vec::new())
+    }
+}
+
+impl DefaultHeaders {
+    pub fn new(headers: Headers, user_agents: Vec<String>) -> Self {
+        let next_user_agent = Cell::new(0);
+        DefaultHeaders {
+            spider_headers: RefCell::new(Headers::new()),
+            headers,
+            user_agents,
+            next_user_agent,
+        }
+    }
+
+    /// Headers of the spider being crawled, including its user agent.
+    pub(crate) fn set_spider_headers(&self, headers: Headers) {
+        *self.spider_headers.borrow_mut() = headers;
+    }
+
+    /// User agents are taken from the pool in turn.
+    fn apply(&self, request: &mut Request) {
+        for header in self.spider_headers.borrow().iter() {
+            if request.headers().get_raw(header.name()).is_none() {
+                request
+                    .headers_mut()
+                    .set_raw(header.name().to_owned(), header.raw().clone());
+            }
+        }
+        if !self.user_agents.is_empty() && request.headers().get_raw("User-Agent").is_none() {
+            let next = self.next_user_agent.get();
+            self.next_user_agent.set((next + 1) % self.user_agents.len());
+            request
+                .headers_mut()
+                .set_raw("User-Agent", self.user_agents[next].clone());
+        }
+        for header in self.headers.iter() {
+            if request.headers().get_raw(header.name()).is_none() {
+                request
+                    .headers_mut()
+                    .set_raw(header.name().to_owned(), header.raw().clone());
+            }
+        }
+    }
+}
+
+/// Executes requests through the configured middleware chain.
+#[derive(Clone)]
+pub struct Downloader {
+    middlewares: Middlewares,
+    default_headers: Rc<DefaultHeaders>,
+    proxies: Rc<ProxyClients>,
+    limits: DownloadLimits,
+    handle: Option<Handle>,
+    workers: Option<DownloadWorkers>,
+    logger: Option<Logger>,
+}
+
+impl Default for Downloader {
+    fn default() -> Self {
+        Downloader::new(Vec::new(), DefaultHeaders::default())
+    }
+}
+
+impl Downloader {
+    pub fn new(
+        middlewares: Vec<Box<DownloaderMiddleware>>,
+        default_headers: DefaultHeaders,
+    ) -> Self {
+        let middlewares = Rc::new(RefCell::new(middlewares));
+        let default_headers = Rc::new(default_headers);
+        let proxies = Rc::new(ProxyClients::default());
+        Downloader {
+            middlewares,
+            default_headers,
+            proxies,
+            limits: DownloadLimits::default(),
+            handle: None,
+            workers: None,
+            logger: None,
+        }
+    }
+
+    /// Timeouts are only enforced if the downloader has a reactor handle.
+    pub fn with_limits(mut self, limits: DownloadLimits, handle: Option<Handle>) -> Self {
+        self.limits = limits;
+        self.handle = handle;
+        self
+    }
+
+    pub fn with_logger(mut self, logger: Logger) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    /// Requests are downloaded by the workers instead of the sheduler client,
+    /// the workers use their own proxy clients and enforce the limits.
+    pub fn with_workers(mut self, workers: DownloadWorkers) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+
+    pub(crate) fn default_headers(&self) -> Rc<DefaultHeaders> {
+        self.default_headers.clone()
+    }
+
+    pub(crate) fn with_proxies(mut self, proxies: ProxyClients) -> Self {
+        self.proxies = Rc::new(proxies);
+        self
+    }
+
+    /// Passes the request through the middlewares, a request they let through
+    /// comes back as `Downloaded::Ready`.
+    pub fn process(&self, mut request: Request) -> DownloadFuture {
+        self.default_headers.apply(&mut request);
+        let middlewares = self.middlewares.clone();
+        let fut = process_request(self.middlewares.clone(), 0, request).map(
+            move |(action, processed)| match action {
+                RequestAction::Continue(request) => Downloaded::Ready(request),
+                RequestAction::Respond(response) => {
+                    // Only middlewares which have seen the request get the response.
+                    let mut middlewares = middlewares.borrow_mut();
+                    process_response(&mut middlewares[..processed], response)
+                }
+                RequestAction::Drop => Downloaded::Dropped,
+                RequestAction::Pending(_) => unreachable!("pending request action was not resolved"),
+            },
+        );
+        Box::new(fut)
+    }
+
+    /// Downloads a request returned by `process`, the response goes through the middlewares.
+    pub fn download(&self, client: &Client, request: Request) -> DownloadFuture {
+        let sent = request.clone();
+        let limits = self.limits.for_request(&request, &self.logger);
+        let fut = match self.workers {
+            Some(ref workers) => workers.execute(request, limits),
+            None => {
+                let client = match self.proxies.client_for(&request) {
+                    Ok(Some(proxied)) => proxied,
+                    Ok(None) => HttpClient::Reqwest(client.clone()),
+                    Err(e) => return Box::new(err(e)),
+                };
+                execute(&client, request, limits, self.handle.clone(), self.logger.clone())
+            }
+        };
+        let middlewares = self.middlewares.clone();
+        let fut = fut.then(move |result| {
+            let mut middlewares = middlewares.borrow_mut();
+            match result {
+                Ok((url, status, headers, body)) => {
+                    let response = Response::new(url, status, headers, body, sent);
+                    Ok(process_response(&mut middlewares[..], response))
+                }
+                Err(e) => process_error(&mut middlewares[..], &sent, e),
+            }
+        });
+        Box::new(fut)
+    }
+}
+
+pub(crate) fn execute(
+    client: &HttpClient,
+    request: Request,
+    limits: DownloadLimits,
+    handle: Option<Handle>,
+    logger: Option<Logger>,
+) -> Box<Future<Item = RawResponse, Error = Error>> {
+    match *client {
+        HttpClient::Reqwest(ref client) => {
+            let fut = client.execute(request.into()).map_err(Error::from).map(|resp| {
+                let head = (resp.url().clone(), resp.status(), resp.headers().clone());
+                (head, resp.into_body().map_err(Error::from))
+            });
+            read_response(fut, limits, handle, logger)
+        }
+        HttpClient::Socks(ref client) => {
+            // hyper doesn't follow redirects, the response url is the request url
+            let url = request.url().clone();
+            let request = match socks_request(request) {
+                Ok(request) => request,
+                Err(e) => return Box::new(err(e)),
+            };
+            let fut = client.request(request).map_err(Error::from).map(move |resp| {
+                let head = (url, resp.status(), resp.headers().clone());
+                (head, resp.body().map_err(Error::from))
+            });
+            read_response(fut, limits, handle, logger)
+        }
+    }
+}
+
+/// Reads the body of a sent request within the limits.
+fn read_response<F, B>(
+    fut: F,
+    limits: DownloadLimits,
+    handle: Option<Handle>,
+    logger: Option<Logger>,
+) -> Box<Future<Item = RawResponse, Error = Error>>
+where
+    F: Future<Item = ((Url, StatusCode, Headers), B), Error = Error> + 'static,
+    B: Stream<Error = Error> + 'static,
+    B::Item: AsRef<[u8]>,
+{
+    let DownloadLimits {
+        max_size,
+        warn_size,
+        connect_timeout,
+        download_timeout,
+    } = limits;
+
+    let fut = with_timeout(fut, connect_timeout, &handle, move || {
+        DownloadError::ConnectTimeout(connect_timeout.unwrap_or_default())
+    });
+
+    let fut = fut.and_then(move |((url, status, headers), body)| {
+        if let (Some(max_size), Some(&ContentLength(length))) =
+            (max_size, headers.get::<ContentLength>())
+        {
+            if length > max_size as u64 {
+                return Box::new(err(DownloadError::TooLarge { max_size }.into()))
+                    as Box<Future<Item = RawResponse, Error = Error>>;
+            }
+        }
+
+        let warn_url = url.clone();
+        let body = body
+            .fold((Vec::new(), false), move |(mut body, mut warned), chunk| {
+                body.extend_from_slice(chunk.as_ref());
+                if let Some(max_size) = max_size {
+                    if body.len() > max_size {
+                        return Err(Error::from(DownloadError::TooLarge { max_size }));
+                    }
+                }
+                if let Some(warn_size) = warn_size {
+                    if !warned && body.len() > warn_size {
+                        warned = true;
+                        if let Some(ref logger) = logger {
+                            warn!(logger, "response is larger than the warning size";
+                                  "url" => %warn_url, "warn_size" => warn_size);
+                        }
+                    }
+                }
+                Ok((body, warned))
+            });
+        Box::new(body.map(move |(body, _)| (url, status, headers, body.into())))
+    });
+
+    with_timeout(fut, download_timeout, &handle, move || {
+        DownloadError::DownloadTimeout(download_timeout.unwrap_or_default())
+    })
+}
+
+fn with_timeout<F, E>(
+    fut: F,
+    timeout: Option<Duration>,
+    handle: &Option<Handle>,
+    error: E,
+) -> Box<Future<Item = F::Item, Error = Error>>
+where
+    F: Future<Error = Error> + 'static,
+    E: FnOnce() -> DownloadError + 'static,
+{
+    let (timeout, handle) = match (timeout, handle) {
+        (Some(timeout), &Some(ref handle)) => (timeout, handle),
+        _ => return Box::new(fut),
+    };
+    match Timeout::new(timeout, handle) {
+        Ok(timeout) => {
+            let timeout = timeout.then(move |_| -> Result<F::Item, Error> { Err(error().into()) });
+            let fut = fut.select(timeout)
+                .map(|(item, _)| item)
+                .map_err(|(e, _)| e);
+            Box::new(fut)
+        }
+        Err(e) => Box::new(err(e.into())),
+    }
+}
+
+/// Runs `process_request` hooks starting from middleware `from`, resolves to the action
+/// and the number of middlewares which have processed the request.
+fn process_request(
+    middlewares: Middlewares,
+    from: usize,
+    request: Request,
+) -> Box<Future<Item = (RequestAction, usize), Error = Error>> {
+    if from == middlewares.borrow().len() {
+        return Box::new(ok((RequestAction::Continue(request), from)));
+    }
+    let action = middlewares.borrow_mut()[from].process_request(request);
+    resume_process_request(middlewares, from, action)
+}
+
+fn resume_process_request(
+    middlewares: Middlewares,
+    current: usize,
+    action: RequestAction,
+) -> Box<Future<Item = (RequestAction, usize), Error = Error>> {
+    match action {
+        RequestAction::Continue(request) => process_request(middlewares, current + 1, request),
+        RequestAction::Pending(fut) => Box::new(
+            fut.and_then(move |action| resume_process_request(middlewares, current, action)),
+        ),
+        other => Box::new(ok((other, current + 1))),
+    }
+}
+
+fn process_response(middlewares: &mut [Box<DownloaderMiddleware>], response: Response) -> Downloaded {
+    let mut response = response;
+    for middleware in middlewares.iter_mut().rev() {
+        match middleware.process_response(response) {
+            ResponseAction::Continue(next) => response = next,
+            ResponseAction::Reschedule(request) => return Downloaded::Reschedule(request),
+            ResponseAction::Drop => return Downloaded::Dropped,
+        }
+    }
+    Downloaded::Response(response)
+}
+
+fn process_error(
+    middlewares: &mut [Box<DownloaderMiddleware>],
+    request: &Request,
+    error: Error,
+) -> Result<Downloaded, Error> {
+    let mut error = error;
+    for i in (0..middlewares.len()).rev() {
+        let action = middlewares[i].process_error(request, error);
+        match action {
+            ErrorAction::Fail(next) => error = next,
+            ErrorAction::Respond(response) => {
+                return Ok(process_response(&mut middlewares[..i + 1], response))
+            }
+            ErrorAction::Reschedule(request) => return Ok(Downloaded::Reschedule(request)),
+            ErrorAction::Drop => return Ok(Downloaded::Dropped),
+        }
+    }
+    Err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Method;
+    use test_server::{response, stalling_server, TestServer};
+    use tokio_core::reactor::Core;
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    /// Records every hook call as `name:hook` and answers with the configured actions.
+    struct Recording {
+        name: &'static str,
+        log: Log,
+        request: fn(Request) -> RequestAction,
+        response: fn(Response) -> ResponseAction,
+        error: fn(&Request, Error) -> ErrorAction,
+    }
+
+    impl DownloaderMiddleware for Recording {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn process_request(&mut self, request: Request) -> RequestAction {
+            self.log.borrow_mut().push(format!("{}:request", self.name));
+            (self.request)(request)
+        }
+
+        fn process_response(&mut self, response: Response) -> ResponseAction {
+            self.log.borrow_mut().push(format!("{}:response", self.name));
+            (self.response)(response)
+        }
+
+        fn process_error(&mut self, request: &Request, error: Error) -> ErrorAction {
+            self.log.borrow_mut().push(format!("{}:error", self.name));
+            (self.error)(request, error)
+        }
+    }
+
+    fn recording(name: &'static str, log: &Log) -> Recording {
+        Recording {
+            name,
+            log: log.clone(),
+            request: RequestAction::Continue,
+            response: ResponseAction::Continue,
+            error: |_, e| ErrorAction::Fail(e),
+        }
+    }
+
+    fn cached(request: Request) -> Response {
+        let url = request.url().clone();
+        Response::new(url, StatusCode::Ok, Headers::new(), Bytes::from("cached"), request)
+    }
+
+    fn get(url: Url) -> Request {
+        Request::new(Method::Get, url)
+    }
+
+    fn log_of(log: &Log) -> Vec<String> {
+        log.borrow().clone()
+    }
+
+    #[test]
+    fn requests_go_forward_and_responses_backward() {
+        let server = TestServer::start(vec![("/", response("200 OK", &[], "page"))]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let log = Log::default();
+        let middlewares: Vec<Box<DownloaderMiddleware>> = vec![
+            Box::new(recording("a", &log)),
+            Box::new(recording("b", &log)),
+            Box::new(recording("c", &log)),
+        ];
+        let downloader = Downloader::new(middlewares, DefaultHeaders::default());
+        let request = match core.run(downloader.process(get(server.url("/")))).unwrap() {
+            Downloaded::Ready(request) => request,
+            _ => panic!("request was not passed to the client"),
+        };
+        match core.run(downloader.download(&client, request)).unwrap() {
+            Downloaded::Response(response) => assert_eq!(response.text(), "page"),
+            _ => panic!("response was not passed through"),
+        }
+        assert_eq!(
+            log_of(&log),
+            vec!["a:request", "b:request", "c:request", "c:response", "b:response", "a:response"]
+        );
+    }
+
+    #[test]
+    fn respond_short_circuits_the_chain() {
+        let mut core = Core::new().unwrap();
+        let log = Log::default();
+        let mut responder = recording("b", &log);
+        responder.request = |request| RequestAction::Respond(cached(request));
+        let middlewares: Vec<Box<DownloaderMiddleware>> = vec![
+            Box::new(recording("a", &log)),
+            Box::new(responder),
+            Box::new(recording("c", &log)),
+        ];
+        let downloader = Downloader::new(middlewares, DefaultHeaders::default());
+        let request = get("http://example.com/".parse().unwrap());
+        match core.run(downloader.process(request)).unwrap() {
+            Downloaded::Response(response) => assert_eq!(response.text(), "cached"),
+            _ => panic!("the middleware response was not used"),
+        }
+        // only the middlewares which have seen the request get the response
+        assert_eq!(log_of(&log), vec!["a:request", "b:request", "b:response", "a:response"]);
+    }
+
+    #[test]
+    fn dropped_requests_stop_the_chain() {
+        let mut core = Core::new().unwrap();
+        let log = Log::default();
+        let mut dropper = recording("b", &log);
+        dropper.request = |_| RequestAction::Drop;
+        let middlewares: Vec<Box<DownloaderMiddleware>> = vec![
+            Box::new(recording("a", &log)),
+            Box::new(dropper),
+            Box::new(recording("c", &log)),
+        ];
+        let downloader = Downloader::new(middlewares, DefaultHeaders::default());
+        let request = get("http://example.com/".parse().unwrap());
+        match core.run(downloader.process(request)).unwrap() {
+            Downloaded::Dropped => {}
+            _ => panic!("request was not dropped"),
+        }
+        assert_eq!(log_of(&log), vec!["a:request", "b:request"]);
+    }
+
+    #[test]
+    fn response_actions_stop_the_chain() {
+        let mut core = Core::new().unwrap();
+        let log = Log::default();
+        let mut rescheduler = recording("b", &log);
+        rescheduler.request = |request| RequestAction::Respond(cached(request));
+        rescheduler.response = |response| {
+            let url = response.url().join("/again").unwrap();
+            ResponseAction::Reschedule(get(url))
+        };
+        let mut dropper = recording("b", &log);
+        dropper.request = |request| RequestAction::Respond(cached(request));
+        dropper.response = |_| ResponseAction::Drop;
+        let request = || get("http://example.com/".parse().unwrap());
+
+        let middlewares: Vec<Box<DownloaderMiddleware>> =
+            vec![Box::new(recording("a", &log)), Box::new(rescheduler)];
+        let downloader = Downloader::new(middlewares, DefaultHeaders::default());
+        match core.run(downloader.process(request())).unwrap() {
+            Downloaded::Reschedule(request) => assert_eq!(request.url().path(), "/again"),
+            _ => panic!("request was not rescheduled"),
+        }
+        assert_eq!(log_of(&log), vec!["a:request", "b:request", "b:response"]);
+
+        log.borrow_mut().clear();
+        let middlewares: Vec<Box<DownloaderMiddleware>> =
+            vec![Box::new(recording("a", &log)), Box::new(dropper)];
+        let downloader = Downloader::new(middlewares, DefaultHeaders::default());
+        match core.run(downloader.process(request())).unwrap() {
+            Downloaded::Dropped => {}
+            _ => panic!("response was not dropped"),
+        }
+        assert_eq!(log_of(&log), vec!["a:request", "b:request", "b:response"]);
+    }
+
+    #[test]
+    fn error_actions() {
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        // nothing listens on the port, so the download fails
+        let refused = || get("http://127.0.0.1:1/".parse().unwrap());
+        let log = Log::default();
+
+        let middlewares: Vec<Box<DownloaderMiddleware>> =
+            vec![Box::new(recording("a", &log)), Box::new(recording("b", &log))];
+        let downloader = Downloader::new(middlewares, DefaultHeaders::default());
+        assert!(core.run(downloader.download(&client, refused())).is_err());
+        assert_eq!(log_of(&log), vec!["b:error", "a:error"]);
+
+        // a recovered response only goes through the middlewares before the recovering one
+        log.borrow_mut().clear();
+        let mut responder = recording("b", &log);
+        responder.error = |request, _| ErrorAction::Respond(cached(request.clone()));
+        let middlewares: Vec<Box<DownloaderMiddleware>> = vec![
+            Box::new(recording("a", &log)),
+            Box::new(responder),
+            Box::new(recording("c", &log)),
+        ];
+        let downloader = Downloader::new(middlewares, DefaultHeaders::default());
+        match core.run(downloader.download(&client, refused())).unwrap() {
+            Downloaded::Response(response) => assert_eq!(response.text(), "cached"),
+            _ => panic!("error was not recovered"),
+        }
+        assert_eq!(log_of(&log), vec!["c:error", "b:error", "b:response", "a:response"]);
+
+        log.borrow_mut().clear();
+        let mut rescheduler = recording("b", &log);
+        rescheduler.error = |request, _| ErrorAction::Reschedule(request.clone());
+        let mut dropper = recording("b", &log);
+        dropper.error = |_, _| ErrorAction::Drop;
+        let middlewares: Vec<Box<DownloaderMiddleware>> =
+            vec![Box::new(recording("a", &log)), Box::new(rescheduler)];
+        let downloader = Downloader::new(middlewares, DefaultHeaders::default());
+        match core.run(downloader.download(&client, refused())).unwrap() {
+            Downloaded::Reschedule(request) => assert_eq!(request.url().port(), Some(1)),
+            _ => panic!("request was not rescheduled"),
+        }
+        let middlewares: Vec<Box<DownloaderMiddleware>> =
+            vec![Box::new(recording("a", &log)), Box::new(dropper)];
+        let downloader = Downloader::new(middlewares, DefaultHeaders::default());
+        match core.run(downloader.download(&client, refused())).unwrap() {
+            Downloaded::Dropped => {}
+            _ => panic!("request was not dropped"),
+        }
+        assert_eq!(log_of(&log), vec!["b:error", "b:error"]);
+    }
+
+    fn limits_for(meta: &[(&str, &str)]) -> DownloadLimits {
+        let limits = DownloadLimits {
+            max_size: Some(100),
+            warn_size: Some(50),
+            connect_timeout: Some(Duration::from_secs(1)),
+            download_timeout: Some(Duration::from_secs(2)),
+        };
+        let mut request = Request::new(Method::Get, "http://example.com".parse().unwrap());
+        for &(key, value) in meta {
+            request.meta_mut().insert(key.to_owned(), value.to_owned());
+        }
+        limits.for_request(&request, &None)
+    }
+
+    #[test]
+    fn meta_overrides_limits() {
+        let limits = limits_for(&[(MAX_SIZE_META_KEY, "10"), (DOWNLOAD_TIMEOUT_META_KEY, "0.5")]);
+        assert_eq!(limits.max_size, Some(10));
+        assert_eq!(limits.warn_size, Some(50));
+        assert_eq!(limits.download_timeout, Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn invalid_meta_falls_back_to_defaults() {
+        let limits = limits_for(&[(MAX_SIZE_META_KEY, "big"), (CONNECT_TIMEOUT_META_KEY, "soon")]);
+        assert_eq!(limits.max_size, Some(100));
+        assert_eq!(limits.connect_timeout, Some(Duration::from_secs(1)));
+    }
+
+    fn download_error(url: &str, limits: DownloadLimits) -> DownloadError {
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let downloader = Downloader::default().with_limits(limits, Some(core.handle()));
+        let request = Request::new(Method::Get, url.parse().unwrap());
+        match core.run(downloader.download(&client, request)) {
+            Ok(_) => panic!("download didn't fail"),
+            Err(e) => e.downcast::<DownloadError>().unwrap(),
+        }
+    }
+
+    #[test]
+    fn bodies_over_max_size_are_too_large() {
+        let server = TestServer::start(vec![("/big", response("200 OK", &[], "0123456789"))]);
+        // no Content-Length, the body is checked while it is read
+        let streaming = stalling_server("HTTP/1.1 200 OK\r\n\r\n0123456789");
+        let limits = DownloadLimits {
+            max_size: Some(5),
+            ..DownloadLimits::default()
+        };
+        for url in &[server.url("/big").to_string(), format!("http://{}/big", streaming)] {
+            match download_error(url, limits.clone()) {
+                DownloadError::TooLarge { max_size: 5 } => {}
+                e => panic!("unexpected error {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn connect_and_download_timeouts_fire() {
+        let silent = stalling_server("");
+        let no_body = stalling_server("HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n");
+        let limits = DownloadLimits {
+            connect_timeout: Some(Duration::from_millis(100)),
+            download_timeout: Some(Duration::from_millis(200)),
+            ..DownloadLimits::default()
+        };
+        match download_error(&format!("http://{}/", silent), limits.clone()) {
+            DownloadError::ConnectTimeout(timeout) => {
+                assert_eq!(timeout, Duration::from_millis(100))
+            }
+            e => panic!("unexpected error {}", e),
+        }
+        match download_error(&format!("http://{}/", no_body), limits) {
+            DownloadError::DownloadTimeout(timeout) => {
+                assert_eq!(timeout, Duration::from_millis(200))
+            }
+            e => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn zero_size_means_no_limit() {
+        let limits = limits_for(&[(MAX_SIZE_META_KEY, "0"), (WARN_SIZE_META_KEY, "0")]);
+        assert_eq!(limits.max_size, None);
+        assert_eq!(limits.warn_size, None);
+    }
+}
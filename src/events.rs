@@ -0,0 +1,64 @@
+use close::CloseReason;
+use reqwest::StatusCode;
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+/// Something that happened during a crawl, see `EventBus`.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// `Spider::opened` resolved, the start requests are sheduled next.
+    SpiderOpened { spider: &'static str },
+    /// The request was passed to the downloader.
+    RequestSheduled { url: Url },
+    /// The request was dropped by a filter or a middleware, the url is unknown
+    /// for requests dropped by downloader middlewares.
+    RequestDropped { url: Option<Url>, reason: &'static str },
+    RequestFailed { error: String },
+    ResponseReceived { url: Url, status: StatusCode },
+    ItemScraped { item: String },
+    ItemDropped { item: String, reason: &'static str },
+    /// No requests are sheduled and no responses are being parsed.
+    SpiderIdle { spider: &'static str },
+    SpiderClosed { spider: &'static str, reason: CloseReason },
+}
+
+type Subscriber = Box<FnMut(&Event) + Send>;
+
+/// Delivers crawl events to subscribers, the handle is cheap to clone.
+///
+/// Subscribers are called synchronously by the engine, so they should be quick
+/// and must not emit events themselves.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+#[allow(dead_code)]
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe<F>(&self, subscriber: F)
+    where
+        F: FnMut(&Event) + Send + 'static,
+    {
+        let mut subscribers = self.subscribers.lock().expect("event bus mutex poisoned");
+        subscribers.push(Box::new(subscriber));
+    }
+
+    /// The event is only built if there are subscribers.
+    pub(crate) fn emit<F>(&self, event: F)
+    where
+        F: FnOnce() -> Event,
+    {
+        let mut subscribers = self.subscribers.lock().expect("event bus mutex poisoned");
+        if subscribers.is_empty() {
+            return;
+        }
+        let event = event();
+        for subscriber in subscribers.iter_mut() {
+            subscriber(&event);
+        }
+    }
+}
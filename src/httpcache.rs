@@ -0,0 +1,559 @@
+use bytes::Bytes;
+use downloader::{DownloaderMiddleware, ErrorAction, RequestAction, ResponseAction};
+use failure::Error;
+use futures::Future;
+use futures_cpupool::CpuPool;
+use request::Request;
+use reqwest::header::{Headers, HttpDate};
+use reqwest::StatusCode;
+use response::Response;
+use slog::Logger;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+use utils::calculate_digest;
+
+/// Meta key set to `"hit"` on the request of responses served from the cache.
+pub const HTTP_CACHE_META_KEY: &str = "httpcache";
+
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum CachePolicy {
+    /// Every response is stored and every cached response is used,
+    /// useful to rerun a spider offline.
+    Always,
+    /// Follows `Cache-Control`, `Expires` and validators of RFC 7234 like a shared cache,
+    /// stale responses are revalidated with conditional requests. `private` responses
+    /// are not stored, `no-cache` responses are always revalidated and a response is
+    /// only used for requests with the same values of the headers listed in its `Vary`.
+    Rfc7234,
+}
+
+#[derive(Clone)]
+struct CacheEntry {
+    url: Url,
+    status: StatusCode,
+    headers: Headers,
+    /// Request headers listed in the `Vary` header of the response.
+    varied: Headers,
+    body: Bytes,
+    stored: SystemTime,
+}
+
+impl<'a> From<&'a Response> for CacheEntry {
+    fn from(response: &'a Response) -> Self {
+        let mut varied = Headers::new();
+        for name in vary(response.headers()) {
+            if let Some(raw) = response.request().headers().get_raw(&name) {
+                varied.set_raw(name, raw.clone());
+            }
+        }
+        CacheEntry {
+            url: response.url().clone(),
+            status: response.status(),
+            headers: response.headers().clone(),
+            varied,
+            body: response.body().clone(),
+            stored: SystemTime::now(),
+        }
+    }
+}
+
+/// Stores responses on disk keyed by the request fingerprint.
+///
+/// Files are read and written on the pool, not on the reactor thread.
+#[allow(dead_code)]
+pub struct HttpCacheMiddleware {
+    dir: PathBuf,
+    policy: CachePolicy,
+    expiration: Option<Duration>,
+    pool: CpuPool,
+    /// Entries of requests being revalidated, a 304 response refreshes them.
+    revalidating: Rc<RefCell<HashMap<PathBuf, CacheEntry>>>,
+    logger: Option<Logger>,
+}
+
+#[allow(dead_code)]
+impl HttpCacheMiddleware {
+    pub fn new<P: Into<PathBuf>>(dir: P, policy: CachePolicy) -> Self {
+        HttpCacheMiddleware {
+            dir: dir.into(),
+            policy,
+            expiration: None,
+            pool: CpuPool::new(1),
+            revalidating: Rc::new(RefCell::new(HashMap::new())),
+            logger: None,
+        }
+    }
+
+    /// Cached responses older than this are ignored whatever the policy says.
+    pub fn with_expiration(mut self, expiration: Duration) -> Self {
+        self.expiration = Some(expiration);
+        self
+    }
+
+    /// Pool for file I/O, e.g. the crawler pool, the middleware has its own thread by default.
+    pub fn with_pool(mut self, pool: CpuPool) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    pub fn with_logger(mut self, logger: Logger) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    fn entry_dir(&self, request: &Request) -> PathBuf {
+        let digest = calculate_digest(request).to_string();
+        self.dir.join(&digest[..2]).join(digest)
+    }
+
+    fn store(&self, dir: PathBuf, entry: CacheEntry, request: &Request) {
+        let logger = self.logger.clone();
+        let request = request.to_string();
+        self.pool
+            .spawn_fn(move || {
+                if let Err(e) = store(&dir, &entry) {
+                    if let Some(ref logger) = logger {
+                        error!(logger, "failed to store cache entry"; "request" => request, "error" => %e);
+                    }
+                }
+                Ok::<(), ()>(())
+            })
+            .forget();
+    }
+}
+
+fn load(dir: &Path, expiration: Option<Duration>) -> Result<Option<CacheEntry>, Error> {
+    let meta = dir.join("meta");
+    if !meta.exists() {
+        return Ok(None);
+    }
+
+    let mut lines = BufReader::new(File::open(meta)?).lines();
+    let status: u16 = next_line(&mut lines)?.parse()?;
+    let status = StatusCode::try_from(status)
+        .map_err(|_| format_err!("wrong status code {}", status))?;
+    let url = next_line(&mut lines)?.parse()?;
+    let stored = UNIX_EPOCH + Duration::from_secs(next_line(&mut lines)?.parse()?);
+    let mut headers = Headers::new();
+    let mut varied = Headers::new();
+    for line in lines {
+        let line = line?;
+        let (headers, line) = match line.strip_prefix(VARIED_PREFIX) {
+            Some(line) => (&mut varied, line),
+            None => (&mut headers, &line[..]),
+        };
+        let mut split = line.splitn(2, ": ");
+        if let (Some(name), Some(value)) = (split.next(), split.next()) {
+            headers.append_raw(name.to_owned(), value.to_owned());
+        }
+    }
+
+    let mut body = Vec::new();
+    File::open(dir.join("body"))?.read_to_end(&mut body)?;
+    let body = body.into();
+
+    let entry = CacheEntry {
+        url,
+        status,
+        headers,
+        varied,
+        body,
+        stored,
+    };
+    match expiration {
+        Some(expiration) if age(entry.stored) > expiration => Ok(None),
+        _ => Ok(Some(entry)),
+    }
+}
+
+/// Prefix of the meta lines with the request headers the response varies on.
+const VARIED_PREFIX: &str = "> ";
+
+/// Files are written aside and renamed, so a concurrent load sees either entry.
+fn store(dir: &Path, entry: &CacheEntry) -> Result<(), Error> {
+    fs::create_dir_all(dir)?;
+    File::create(dir.join("body.tmp"))?.write_all(&entry.body)?;
+    fs::rename(dir.join("body.tmp"), dir.join("body"))?;
+
+    // meta is written last, an entry without it is incomplete and ignored
+    let mut meta = File::create(dir.join("meta.tmp"))?;
+    writeln!(meta, "{}", entry.status.as_u16())?;
+    writeln!(meta, "{}", entry.url)?;
+    let stored = entry.stored.duration_since(UNIX_EPOCH)?;
+    writeln!(meta, "{}", stored.as_secs())?;
+    for header in entry.headers.iter() {
+        for value in header.raw().iter() {
+            if let Ok(value) = str::from_utf8(value) {
+                writeln!(meta, "{}: {}", header.name(), value)?;
+            }
+        }
+    }
+    for header in entry.varied.iter() {
+        for value in header.raw().iter() {
+            if let Ok(value) = str::from_utf8(value) {
+                writeln!(meta, "{}{}: {}", VARIED_PREFIX, header.name(), value)?;
+            }
+        }
+    }
+    drop(meta);
+    fs::rename(dir.join("meta.tmp"), dir.join("meta"))?;
+    Ok(())
+}
+
+/// Updates the entry with the headers of a 304 response, as of RFC 7234 section 4.3.4.
+fn refresh(mut entry: CacheEntry, headers: &Headers) -> CacheEntry {
+    for header in headers.iter() {
+        let name = header.name();
+        if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding") {
+            continue;
+        }
+        entry.headers.set_raw(name.to_owned(), header.raw().clone());
+    }
+    entry.stored = SystemTime::now();
+    entry
+}
+
+fn is_fresh(policy: &CachePolicy, entry: &CacheEntry, request: &Request) -> bool {
+    match *policy {
+        CachePolicy::Always => true,
+        CachePolicy::Rfc7234 => {
+            if cache_control(request.headers(), "no-cache").is_some()
+                || cache_control(&entry.headers, "no-cache").is_some()
+                || cache_control(&entry.headers, "private").is_some()
+                || !vary_matches(entry, request)
+            {
+                return false;
+            }
+            match freshness_lifetime(&entry.headers) {
+                Some(lifetime) => age(entry.stored) < lifetime,
+                None => false,
+            }
+        }
+    }
+}
+
+fn should_store(policy: &CachePolicy, response: &Response) -> bool {
+    match *policy {
+        CachePolicy::Always => true,
+        CachePolicy::Rfc7234 => {
+            let status = response.status();
+            (status.is_success() || status.is_redirection())
+                && status != StatusCode::NotModified
+                && cache_control(response.headers(), "no-store").is_none()
+                && cache_control(response.headers(), "private").is_none()
+                && cache_control(response.request().headers(), "no-store").is_none()
+                && !vary(response.headers()).iter().any(|name| name == "*")
+        }
+    }
+}
+
+fn respond(entry: CacheEntry, mut request: Request) -> Response {
+    request
+        .meta_mut()
+        .insert(HTTP_CACHE_META_KEY.to_owned(), "hit".to_owned());
+    Response::new(entry.url, entry.status, entry.headers, entry.body, request)
+}
+
+fn next_line<B: BufRead>(lines: &mut ::std::io::Lines<B>) -> Result<String, Error> {
+    match lines.next() {
+        Some(line) => Ok(line?),
+        None => Err(format_err!("cache entry is truncated")),
+    }
+}
+
+fn age(stored: SystemTime) -> Duration {
+    SystemTime::now()
+        .duration_since(stored)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+}
+
+fn header_str(headers: &Headers, name: &str) -> Option<String> {
+    headers
+        .get_raw(name)
+        .and_then(|raw| raw.one())
+        .and_then(|value| str::from_utf8(value).ok())
+        .map(|value| value.to_owned())
+}
+
+fn header_date(headers: &Headers, name: &str) -> Option<SystemTime> {
+    header_str(headers, name)
+        .and_then(|value| value.parse::<HttpDate>().ok())
+        .map(|date| date.into())
+}
+
+/// Returns the value of the `Cache-Control` directive, empty if it has no value.
+fn cache_control(headers: &Headers, directive: &str) -> Option<String> {
+    let value = header_str(headers, "Cache-Control")?;
+    for part in value.split(',') {
+        let mut split = part.splitn(2, '=');
+        let name = split.next().unwrap_or("").trim().to_lowercase();
+        if name == directive {
+            let value = split.next().unwrap_or("").trim().trim_matches('"');
+            return Some(value.to_owned());
+        }
+    }
+    None
+}
+
+/// Header names listed in `Vary`, lowercase.
+fn vary(headers: &Headers) -> Vec<String> {
+    let raw = match headers.get_raw("Vary") {
+        Some(raw) => raw,
+        None => return Vec::new(),
+    };
+    raw.iter()
+        .filter_map(|value| str::from_utf8(value).ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// The request has the same values of the headers the entry varies on as the stored one.
+fn vary_matches(entry: &CacheEntry, request: &Request) -> bool {
+    vary(&entry.headers).iter().all(|name| {
+        name != "*" && entry.varied.get_raw(name) == request.headers().get_raw(name)
+    })
+}
+
+fn freshness_lifetime(headers: &Headers) -> Option<Duration> {
+    if let Some(max_age) = cache_control(headers, "max-age") {
+        return max_age.parse().ok().map(Duration::from_secs);
+    }
+    let date = header_date(headers, "Date");
+    if let (Some(expires), Some(date)) = (header_date(headers, "Expires"), date) {
+        return Some(expires.duration_since(date).unwrap_or_else(|_| Duration::from_secs(0)));
+    }
+    // heuristic freshness, 10% of the time since the last modification
+    if let (Some(modified), Some(date)) = (header_date(headers, "Last-Modified"), date) {
+        if let Ok(since) = date.duration_since(modified) {
+            return Some(since / 10);
+        }
+    }
+    None
+}
+
+impl DownloaderMiddleware for HttpCacheMiddleware {
+    fn name(&self) -> &'static str {
+        "HttpCacheMiddleware"
+    }
+
+    fn process_request(&mut self, request: Request) -> RequestAction {
+        let dir = self.entry_dir(&request);
+        let expiration = self.expiration;
+        let loading = dir.clone();
+        let loaded = self.pool.spawn_fn(move || load(&loading, expiration));
+
+        let policy = self.policy.clone();
+        let revalidating = self.revalidating.clone();
+        let logger = self.logger.clone();
+        let fut = loaded.then(move |loaded| {
+            let mut request = request;
+            let entry = match loaded {
+                Ok(Some(entry)) => entry,
+                Ok(None) => return Ok(RequestAction::Continue(request)),
+                Err(e) => {
+                    if let Some(ref logger) = logger {
+                        error!(logger, "failed to read cache entry"; "request" => %request, "error" => %e);
+                    }
+                    return Ok(RequestAction::Continue(request));
+                }
+            };
+            if is_fresh(&policy, &entry, &request) {
+                return Ok(RequestAction::Respond(respond(entry, request)));
+            }
+            // revalidate, a 304 response is replaced by the cached one
+            if let Some(etag) = header_str(&entry.headers, "ETag") {
+                request.headers_mut().set_raw("If-None-Match", etag);
+            }
+            if let Some(modified) = header_str(&entry.headers, "Last-Modified") {
+                request.headers_mut().set_raw("If-Modified-Since", modified);
+            }
+            revalidating.borrow_mut().insert(dir, entry);
+            Ok(RequestAction::Continue(request))
+        });
+        RequestAction::Pending(Box::new(fut))
+    }
+
+    fn process_response(&mut self, response: Response) -> ResponseAction {
+        if response.request().meta().contains_key(HTTP_CACHE_META_KEY) {
+            return ResponseAction::Continue(response);
+        }
+        let dir = self.entry_dir(response.request());
+        let revalidated = self.revalidating.borrow_mut().remove(&dir);
+        if response.status() == StatusCode::NotModified {
+            if let Some(entry) = revalidated {
+                let entry = refresh(entry, response.headers());
+                self.store(dir, entry.clone(), response.request());
+                let request = response.request().clone();
+                return ResponseAction::Continue(respond(entry, request));
+            }
+        }
+        if should_store(&self.policy, &response) {
+            self.store(dir, CacheEntry::from(&response), response.request());
+        }
+        ResponseAction::Continue(response)
+    }
+
+    fn process_error(&mut self, request: &Request, error: Error) -> ErrorAction {
+        let dir = self.entry_dir(request);
+        self.revalidating.borrow_mut().remove(&dir);
+        ErrorAction::Fail(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use body::Body;
+    use downloader::{DefaultHeaders, Downloaded, Downloader};
+    use reqwest::unstable::async::Client;
+    use reqwest::Method;
+    use std::env;
+    use std::thread;
+    use test_server::{response, TestServer};
+    use tokio_core::reactor::Core;
+
+    fn fetch(core: &mut Core, downloader: &Downloader, client: &Client, url: &Url) -> Response {
+        let request = Request::new(Method::Get, url.clone());
+        let downloaded = match core.run(downloader.process(request)).unwrap() {
+            Downloaded::Ready(request) => core.run(downloader.download(client, request)).unwrap(),
+            downloaded => downloaded,
+        };
+        match downloaded {
+            Downloaded::Response(response) => response,
+            _ => panic!("no response"),
+        }
+    }
+
+    /// Entries are stored in the background, waits until the meta file contains the text.
+    fn wait_for_meta(dir: &Path, text: &str) {
+        for _ in 0..200 {
+            let mut meta = String::new();
+            if let Ok(mut file) = File::open(dir.join("meta")) {
+                let _ = file.read_to_string(&mut meta);
+            }
+            if meta.contains(text) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("cache entry was not stored");
+    }
+
+    #[test]
+    fn not_modified_refreshes_the_entry() {
+        let server = TestServer::start(vec![
+            (
+                "/page",
+                response("200 OK", &[("ETag", "\"v1\""), ("Cache-Control", "max-age=0")], "cached"),
+            ),
+            (
+                "/page",
+                response("304 Not Modified", &[("ETag", "\"v1\""), ("Cache-Control", "max-age=3600")], ""),
+            ),
+        ]);
+        let dir = env::temp_dir().join(format!("httpcache-test-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cache = HttpCacheMiddleware::new(dir.clone(), CachePolicy::Rfc7234);
+        let url = server.url("/page");
+        let entry_dir = cache.entry_dir(&Request::new(Method::Get, url.clone()));
+        let downloader = Downloader::new(vec![Box::new(cache)], DefaultHeaders::default());
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+
+        let first = fetch(&mut core, &downloader, &client, &url);
+        assert_eq!(first.body().as_ref(), b"cached");
+        wait_for_meta(&entry_dir, "max-age=0");
+
+        let revalidated = fetch(&mut core, &downloader, &client, &url);
+        assert_eq!(revalidated.status(), StatusCode::Ok);
+        assert_eq!(revalidated.body().as_ref(), b"cached");
+        wait_for_meta(&entry_dir, "max-age=3600");
+
+        let hit = fetch(&mut core, &downloader, &client, &url);
+        assert_eq!(hit.body().as_ref(), b"cached");
+        assert_eq!(hit.request().meta().get(HTTP_CACHE_META_KEY).map(|s| &s[..]), Some("hit"));
+        assert_eq!(server.requests(), vec!["/page", "/page"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn cached(request: Request, headers: &[(&str, &str)]) -> Response {
+        let mut response_headers = Headers::new();
+        for &(name, value) in headers {
+            response_headers.set_raw(name.to_owned(), value.to_owned());
+        }
+        let url = request.url().clone();
+        Response::new(url, StatusCode::Ok, response_headers, Bytes::new(), request)
+    }
+
+    fn with_language(language: Option<&str>) -> Request {
+        let mut request = Request::new(Method::Get, "http://example.com/".parse().unwrap());
+        if let Some(language) = language {
+            request.headers_mut().set_raw("Accept-Language", language.to_owned());
+        }
+        request
+    }
+
+    #[test]
+    fn response_cache_control_is_honoured() {
+        let policy = CachePolicy::Rfc7234;
+        let fresh = |headers: &[(&str, &str)]| {
+            let response = cached(with_language(None), headers);
+            is_fresh(&policy, &CacheEntry::from(&response), &with_language(None))
+        };
+        assert!(fresh(&[("Cache-Control", "max-age=3600")]));
+        assert!(!fresh(&[("Cache-Control", "max-age=3600, no-cache")]));
+        assert!(!fresh(&[("Cache-Control", "private, max-age=3600")]));
+
+        let private = cached(with_language(None), &[("Cache-Control", "private, max-age=3600")]);
+        assert!(!should_store(&policy, &private));
+        assert!(should_store(&CachePolicy::Always, &private));
+        let any = cached(with_language(None), &[("Cache-Control", "max-age=3600"), ("Vary", "*")]);
+        assert!(!should_store(&policy, &any));
+    }
+
+    #[test]
+    fn vary_selects_requests_the_entry_is_used_for() {
+        let policy = CachePolicy::Rfc7234;
+        let headers = [
+            ("Cache-Control", "max-age=3600"),
+            ("Vary", "Accept-Encoding, accept-language"),
+        ];
+        let response = cached(with_language(Some("en")), &headers);
+        let entry = CacheEntry::from(&response);
+
+        let dir = env::temp_dir().join(format!("httpcache-vary-test-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        store(&dir, &entry).unwrap();
+        let loaded = load(&dir, None).unwrap().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        for entry in &[entry, loaded] {
+            assert!(is_fresh(&policy, entry, &with_language(Some("en"))));
+            assert!(!is_fresh(&policy, entry, &with_language(Some("de"))));
+            assert!(!is_fresh(&policy, entry, &with_language(None)));
+        }
+    }
+
+    #[test]
+    fn entries_of_other_hosts_and_bodies_are_separate() {
+        let cache = HttpCacheMiddleware::new("cache", CachePolicy::Always);
+        let request = |url: &str| Request::new(Method::Get, url.parse().unwrap());
+        let a = cache.entry_dir(&request("http://a.com/x"));
+        assert_ne!(a, cache.entry_dir(&request("http://b.com/x")));
+        assert_ne!(a, cache.entry_dir(&request("http://a.com:8080/x")));
+        assert_eq!(a, cache.entry_dir(&request("http://a.com:80/x")));
+        let mut post = request("http://a.com/x");
+        *post.body_mut() = Some(Body::from("body"));
+        assert_ne!(a, cache.entry_dir(&post));
+    }
+}
@@ -0,0 +1,38 @@
+use failure::Error;
+use seen::{SeenSet, SeenStorage};
+
+/// Drops items whose key was already produced during the crawl.
+///
+/// The key is computed by a user provided function, e.g. a product id,
+/// so the same item found on several pages is only emitted once.
+pub struct ItemFilter<T> {
+    key: Box<Fn(&T) -> String>,
+    seen: SeenSet<String>,
+}
+
+#[allow(dead_code)]
+impl<T> ItemFilter<T> {
+    pub fn new<F>(key: F, storage: &SeenStorage) -> Result<Self, Error>
+    where
+        F: Fn(&T) -> String + 'static,
+    {
+        let key = Box::new(key);
+        let seen = SeenSet::open(storage)?;
+        Ok(ItemFilter { key, seen })
+    }
+
+    pub fn in_memory<F>(key: F) -> Self
+    where
+        F: Fn(&T) -> String + 'static,
+    {
+        let key = Box::new(key);
+        let seen = SeenSet::in_memory();
+        ItemFilter { key, seen }
+    }
+
+    /// Returns `true` if an item with the same key was seen before.
+    pub fn is_duplicate(&mut self, item: &T) -> Result<bool, Error> {
+        let key = (self.key)(item);
+        self.seen.check_and_insert(key)
+    }
+}
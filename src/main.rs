@@ -1,15 +1,28 @@
 #![feature(never_type)]
 #![feature(conservative_impl_trait)]
+#![cfg_attr(test, feature(test))]
 
+extern crate base64;
 extern crate ex_futures;
 #[macro_use]
 extern crate futures;
+#[macro_use]
 extern crate failure;
 extern crate futures_cpupool;
+extern crate hyper;
+extern crate hyper_tls;
+extern crate native_tls;
 extern crate reqwest;
 extern crate select;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate sha1;
 extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_signal;
+extern crate toml;
 extern crate url;
 #[macro_use]
 extern crate failure_derive;
@@ -18,24 +31,56 @@ extern crate failure_derive;
 extern crate slog;
 extern crate bytes;
 extern crate sloggers;
+#[cfg(test)]
+extern crate test;
 
+mod async_spider;
 mod body;
+mod client;
+mod close;
+mod context;
+mod cookies;
 mod crawler;
+mod downloader;
 mod eos_on_error;
+mod events;
 mod fork;
+mod httpcache;
+mod item_filter;
+mod offsite;
+mod process;
+mod proxy;
+mod redirect;
 mod request;
+mod response;
+mod robots;
+mod seen;
+mod settings;
 mod select_all;
 mod sheduler;
+mod socks;
 mod spider;
+mod shutdown;
+mod spider_middleware;
+mod start;
+mod stats;
+#[cfg(test)]
+mod test_server;
 mod utils;
+mod wake_fork;
+mod workers;
+use context::CrawlContext;
+use cookies::CookiesMiddleware;
 use crawler::CrawlerBuilder;
 use failure::Error;
-use futures::future::{err, ok};
+use futures::future::{err, lazy, ok};
 use futures::stream::{iter_ok, once};
 use futures::Future;
-use futures::Stream;
+use item_filter::ItemFilter;
+use redirect::RedirectMiddleware;
 use request::Request;
-use reqwest::unstable::async::{Client, Response};
+use response::Response;
+use robots::RobotsTxtMiddleware;
 use reqwest::Method;
 use select::document::Document;
 use select::predicate::{Attr, Class, Name, Predicate};
@@ -43,6 +88,10 @@ use sloggers::terminal::{Destination, TerminalLoggerBuilder};
 use sloggers::types::Severity;
 use sloggers::Build;
 use spider::Parse;
+use spider_middleware::{DepthMiddleware, RefererMiddleware};
+use start::StartFormat;
+use stats::Stats;
+use std::env;
 use std::fmt::{self, Display};
 use url::{ParseError, Url};
 
@@ -74,7 +123,10 @@ impl spider::Spider for Dummy {
         "DummySpider"
     }
 
-    fn start(&mut self) -> Box<Future<Item = spider::RequestStream, Error = Error>> {
+    fn start(
+        &mut self,
+        _context: &CrawlContext,
+    ) -> Box<Future<Item = spider::RequestStream, Error = Error>> {
         let url = "https://google.com".parse().map_err(|e: ParseError| {
             let e: Error = e.into();
             e
@@ -95,6 +147,7 @@ impl spider::Spider for Dummy {
     fn parse(
         &mut self,
         _resp: Response,
+        _context: &CrawlContext,
     ) -> Box<Future<Item = spider::ParseStream<Self::Item>, Error = Error> + Send> {
         let req = "https://google.com"
             .parse()
@@ -119,51 +172,54 @@ impl fmt::Display for XnxxItem {
     }
 }
 
-pub struct XnxxSpider<'a> {
-    client: &'a Client,
+pub struct XnxxSpider {
+    start: Option<spider::RequestStream>,
 }
 
-impl<'a> XnxxSpider<'a> {
-    fn new(client: &'a Client) -> Self {
-        Self { client }
+impl XnxxSpider {
+    /// Starts with the requests if there are any, with the tags page otherwise.
+    fn new(start: Option<spider::RequestStream>) -> Self {
+        XnxxSpider { start }
     }
 }
 
-impl<'a> spider::Spider for XnxxSpider<'a> {
+impl spider::Spider for XnxxSpider {
     type Item = XnxxItem;
 
     fn name(&self) -> &'static str {
         "XnxxSpider"
     }
 
-    fn start(&mut self) -> Box<Future<Item = spider::RequestStream, Error = Error>> {
+    fn allowed_domains(&self) -> &[&'static str] {
+        &["xnxx.com"]
+    }
+
+    fn start(
+        &mut self,
+        context: &CrawlContext,
+    ) -> Box<Future<Item = spider::RequestStream, Error = Error>> {
+        if let Some(requests) = self.start.take() {
+            return Box::new(ok(requests));
+        }
         let url: Result<Url, ParseError> = "http://www.xnxx.com/tags".parse();
         match url {
             Ok(url) => {
                 let req = Request::new(Method::Get, url.clone());
-                let fut = self.client
-                    .execute(req.into())
-                    .map_err(|e| e.into())
-                    .and_then(|resp| {
-                        let body = resp.into_body();
-                        body.concat2().map_err(|e| e.into())
-                    })
-                    .map(move |body| {
-                        let body = body.to_owned();
-                        let body = String::from_utf8_lossy(&body);
-                        let doc = Document::from(body.as_ref());
-                        let mut output = Vec::new();
-                        for tag in doc.find(Attr("id", "tags").descendant(Name("a"))).take(1) {
-                            let href = tag.attr("href");
-                            if let Some(href) = href {
-                                let new = url.join(href)
-                                    .map(|url| Request::new(Method::Get, url))
-                                    .map_err(|e| e.into());
-                                output.push(new);
-                            }
+                let fut = context.fetch(req).map(move |resp| {
+                    let body = resp.text();
+                    let doc = Document::from(body.as_ref());
+                    let mut output = Vec::new();
+                    for tag in doc.find(Attr("id", "tags").descendant(Name("a"))).take(1) {
+                        let href = tag.attr("href");
+                        if let Some(href) = href {
+                            let new = url.join(href)
+                                .map(|url| Request::new(Method::Get, url))
+                                .map_err(|e| e.into());
+                            output.push(new);
                         }
-                        Box::new(iter_ok(output)) as spider::RequestStream
-                    });
+                    }
+                    Box::new(iter_ok(output)) as spider::RequestStream
+                });
                 Box::new(fut)
             }
             Err(e) => Box::new(err(e.into())),
@@ -173,53 +229,84 @@ impl<'a> spider::Spider for XnxxSpider<'a> {
     fn parse(
         &mut self,
         resp: Response,
+        _context: &CrawlContext,
     ) -> Box<Future<Item = spider::ParseStream<Self::Item>, Error = Error> + Send> {
-        let url = resp.url().clone();
-
-        let fut = resp.into_body()
-            .concat2()
-            .map_err(|e| e.into())
-            .map(move |body| {
-                let body = body.to_owned();
-                let body = String::from_utf8_lossy(&body);
-                let doc = Document::from(body.as_ref());
-                let mut requests = Vec::new();
-                //let mut items = Vec::new();
-                for tag in doc.find(Class("pagination").descendant(Name("a"))) {
-                    let href = tag.attr("href");
-                    if let Some(href) = href {
-                        let new = url.join(href).expect("Wrong url");
-                        //let item = XnxxItem { url: new.clone() };
-                        //items.push(Ok(spider::Parse::Item(item)));
-                        requests.push(Ok(spider::Parse::Request(Request::new(Method::Get, new))));
-                    }
+        let fut = lazy(move || {
+            let url = resp.url();
+            let body = resp.text();
+            let doc = Document::from(body.as_ref());
+            let mut requests = Vec::new();
+            //let mut items = Vec::new();
+            for tag in doc.find(Class("pagination").descendant(Name("a"))) {
+                let href = tag.attr("href");
+                if let Some(href) = href {
+                    let new = url.join(href).expect("Wrong url");
+                    //let item = XnxxItem { url: new.clone() };
+                    //items.push(Ok(spider::Parse::Item(item)));
+                    requests.push(Ok(spider::Parse::Request(Request::new(Method::Get, new))));
                 }
-                let req_stream = iter_ok(requests.into_iter());
-                //let item_stream = iter_ok(items.into_iter());
-                //let stream = req_stream.select(item_stream);
-                //Box::new(stream) as spider::ParseStream<Self::Item>
-                Box::new(req_stream) as spider::ParseStream<Self::Item>
-            });
+            }
+            let req_stream = iter_ok(requests.into_iter());
+            //let item_stream = iter_ok(items.into_iter());
+            //let stream = req_stream.select(item_stream);
+            //Box::new(stream) as spider::ParseStream<Self::Item>
+            Ok(Box::new(req_stream) as spider::ParseStream<Self::Item>)
+        });
         Box::new(fut)
     }
 }
 
 fn main() {
     let mut core = tokio_core::reactor::Core::new().unwrap();
-    let client = Client::new(&core.handle());
+    let client_config = client::ClientConfig::default();
+    let client = client_config.build(&core.handle()).unwrap();
     let mut builder = TerminalLoggerBuilder::new();
     builder.level(Severity::Debug);
     builder.destination(Destination::Stderr);
     let logger = builder.build().unwrap();
 
-    let sheduler = sheduler::GlobalLimitedSheduler::with_logger(&client, 2, logger.clone());
-    let crawler = CrawlerBuilder::new(sheduler)
-        .with_logger(logger)
+    let host_delays = sheduler::HostDelays::new(core.handle());
+    let mut sheduler = sheduler::GlobalLimitedSheduler::with_logger(&client, 2, logger.clone());
+    sheduler.set_host_delays(host_delays.clone());
+    let settings = settings::Settings::from_env().unwrap();
+    let user_agent = settings
+        .user_agent
+        .clone()
+        .unwrap_or_else(|| crawler::DEFAULT_USER_AGENT.to_owned());
+    let stats = Stats::new();
+    let builder = CrawlerBuilder::new(sheduler)
+        .with_client_config(client_config)
+        .with_settings(&settings)
+        .with_logger(logger.clone())
+        .with_handle(core.handle())
+        .with_stats(stats.clone());
+    let robots = RobotsTxtMiddleware::new(client.clone(), &user_agent)
+        .with_host_delays(host_delays)
+        .with_limits(builder.download_limits(), Some(core.handle()))
+        .with_stats(stats)
+        .with_logger(logger.clone());
+    let redirect = RedirectMiddleware::new(10).with_logger(logger.clone());
+    let crawler = builder
+        .with_downloader_middleware(robots)
+        .with_downloader_middleware(redirect)
+        .with_downloader_middleware(CookiesMiddleware::new())
         .build()
         .unwrap();
-    let spider = XnxxSpider::new(&client);
-    let crawl = crawler.crawl(spider);
-    let crawl = crawl.for_each(|_item| Ok(()));
+    // start requests from the file given as the first argument, `-` reads urls from stdin
+    let start_requests = match env::args().nth(1) {
+        Some(ref path) if path == "-" => Some(start::from_stdin(StartFormat::Urls)),
+        Some(path) => Some(start::from_file(&path, StartFormat::for_path(&path)).unwrap()),
+        None => None,
+    };
+    let spider = XnxxSpider::new(start_requests);
+    let item_filter = ItemFilter::in_memory(|item: &XnxxItem| item.url.to_string());
+    let crawl = crawler
+        .crawl(spider)
+        .with_item_filter(item_filter)
+        .with_spider_middleware(DepthMiddleware::with_max_depth(3))
+        .with_spider_middleware(RefererMiddleware);
+    shutdown::shutdown_on_signals(&core.handle(), crawl.shutdown_handle(), Some(logger));
+    let crawl = crawl.run(|_item| Ok(()));
 
     let res = core.run(crawl);
     println!("{:?}", res);
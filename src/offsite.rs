@@ -0,0 +1,122 @@
+use request::Request;
+use slog::Logger;
+use std::collections::HashMap;
+
+/// Drops requests to hosts outside of `Spider::allowed_domains`.
+///
+/// A domain also allows all of its subdomains. An empty list allows everything.
+pub(crate) struct OffsiteFilter {
+    allowed: Vec<String>,
+    dropped: HashMap<String, u64>,
+    logger: Option<Logger>,
+}
+
+impl OffsiteFilter {
+    pub fn new(allowed_domains: &[&str], logger: Option<Logger>) -> Self {
+        let allowed = allowed_domains
+            .iter()
+            .map(|domain| domain.trim_left_matches('.').to_lowercase())
+            .collect();
+        let dropped = HashMap::new();
+        OffsiteFilter {
+            allowed,
+            dropped,
+            logger,
+        }
+    }
+
+    pub fn allows(&self, host: &str) -> bool {
+        if self.allowed.is_empty() {
+            return true;
+        }
+        self.allowed.iter().any(|domain| {
+            host == domain
+                || (host.ends_with(domain.as_str())
+                    && host[..host.len() - domain.len()].ends_with('.'))
+        })
+    }
+
+    /// Returns `true` if the request may be sheduled, the first dropped request
+    /// to every host is logged, the rest are only counted.
+    pub fn filter(&mut self, request: &Request) -> bool {
+        let host = match request.url().host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return true,
+        };
+        if self.allows(&host) {
+            return true;
+        }
+        let count = self.dropped.entry(host).or_insert(0);
+        if *count == 0 {
+            if let Some(ref logger) = self.logger {
+                info!(logger, "offsite request filtered, further requests to the host will not be logged";
+                      "request" => %request);
+            }
+        }
+        *count += 1;
+        false
+    }
+
+    /// Number of dropped requests by host.
+    pub fn dropped(&self) -> &HashMap<String, u64> {
+        &self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Method;
+    use slog::{Drain, Never, OwnedKVList, Record};
+    use std::sync::{Arc, Mutex};
+
+    /// Collects the messages of all records.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl Drain for Capture {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record, _: &OwnedKVList) -> Result<(), Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    fn request(url: &str) -> Request {
+        Request::new(Method::Get, url.parse().unwrap())
+    }
+
+    #[test]
+    fn allowed_domains_include_subdomains() {
+        let filter = OffsiteFilter::new(&["example.com", ".Other.org"], None);
+        assert!(filter.allows("example.com"));
+        assert!(filter.allows("www.example.com"));
+        assert!(filter.allows("a.b.example.com"));
+        assert!(filter.allows("other.org"));
+        assert!(!filter.allows("badexample.com"));
+        assert!(!filter.allows("example.com.evil.net"));
+        assert!(!filter.allows("com"));
+
+        let filter = OffsiteFilter::new(&[], None);
+        assert!(filter.allows("anything.net"));
+    }
+
+    #[test]
+    fn first_drop_per_host_is_logged_and_all_are_counted() {
+        let capture = Capture::default();
+        let logger = Logger::root(capture.clone().fuse(), o!());
+        let mut filter = OffsiteFilter::new(&["example.com"], Some(logger));
+        assert!(filter.filter(&request("http://WWW.example.com/a")));
+        assert!(!filter.filter(&request("http://evil.net/a")));
+        assert!(!filter.filter(&request("http://evil.net/b")));
+        assert!(!filter.filter(&request("http://other.net/")));
+        assert!(!filter.filter(&request("http://EVIL.net/c")));
+
+        assert_eq!(capture.0.lock().unwrap().len(), 2);
+        assert_eq!(filter.dropped().get("evil.net"), Some(&3));
+        assert_eq!(filter.dropped().get("other.net"), Some(&1));
+        assert_eq!(filter.dropped().len(), 2);
+    }
+}
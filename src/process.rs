@@ -0,0 +1,193 @@
+use crawler::Crawl;
+use failure::Error;
+use futures::{Poll, Stream};
+use select_all::SelectAll;
+use sheduler::{GlobalLimit, Sheduler};
+use shutdown::ShutdownHandle;
+use spider::Spider;
+use stats::{Stats, StatsSnapshot};
+
+type TaggedStream<'a, T> = Box<Stream<Item = (&'static str, T), Error = Error> + 'a>;
+
+/// Runs crawls of several spiders at once on one reactor and merges their items,
+/// every item is tagged with the name of its spider.
+///
+/// Only per-spider shedulers are supported, there is no shared sheduler mode: every
+/// spider needs its own `Crawler`, because a sheduler returns responses to the crawl
+/// which polls it and keeps the stats, downloader and close state of that crawl.
+/// What a shared sheduler would give, one concurrency budget for all spiders, is
+/// `with_global_limit`, which limits downloads of all crawls together.
+pub struct CrawlerProcess<'a, T> {
+    crawls: SelectAll<TaggedStream<'a, T>>,
+    stats: Vec<(&'static str, Stats)>,
+    shutdown: Vec<ShutdownHandle>,
+    global_limit: Option<GlobalLimit>,
+    limited: Vec<Box<Fn(GlobalLimit) + 'a>>,
+}
+
+impl<'a, T> Default for CrawlerProcess<'a, T> {
+    fn default() -> Self {
+        CrawlerProcess::new()
+    }
+}
+
+#[allow(dead_code)]
+impl<'a, T> CrawlerProcess<'a, T> {
+    pub fn new() -> Self {
+        CrawlerProcess {
+            crawls: SelectAll::new(),
+            stats: Vec::new(),
+            shutdown: Vec::new(),
+            global_limit: None,
+            limited: Vec::new(),
+        }
+    }
+
+    /// Limits downloads in progress of all crawls together, including crawls added before.
+    pub fn with_global_limit(mut self, limit: usize) -> Self {
+        let global_limit = GlobalLimit::new(limit);
+        for set_limit in &self.limited {
+            set_limit(global_limit.clone());
+        }
+        self.global_limit = Some(global_limit);
+        self
+    }
+
+    pub fn with_crawl<S, SH>(mut self, crawl: Crawl<S, SH>) -> Self
+    where
+        S: Spider<Item = T> + 'a,
+        SH: Sheduler + 'a,
+        T: Send + 'static,
+    {
+        let name = crawl.spider().name();
+        self.stats.push((name, crawl.stats().clone()));
+        self.shutdown.push(crawl.shutdown_handle());
+        let sheduler = crawl.sheduler();
+        if let Some(ref global_limit) = self.global_limit {
+            sheduler.borrow_mut().set_global_limit(global_limit.clone());
+        }
+        self.limited.push(Box::new(move |global_limit| {
+            sheduler.borrow_mut().set_global_limit(global_limit)
+        }));
+        let crawl = crawl.map(move |item| (name, item));
+        self.crawls.push(Box::new(crawl));
+        self
+    }
+
+    /// Stats of all crawls added together.
+    pub fn stats(&self) -> StatsSnapshot {
+        let mut merged = StatsSnapshot::default();
+        for &(_, ref stats) in &self.stats {
+            merged.merge(&stats.snapshot());
+        }
+        merged
+    }
+
+    pub fn spider_stats(&self) -> Vec<(&'static str, StatsSnapshot)> {
+        self.stats
+            .iter()
+            .map(|&(name, ref stats)| (name, stats.snapshot()))
+            .collect()
+    }
+
+    /// Shuts every crawl down, see `ShutdownHandle::shutdown`.
+    pub fn shutdown(&self) {
+        for handle in &self.shutdown {
+            handle.shutdown();
+        }
+    }
+}
+
+impl<'a, T> Stream for CrawlerProcess<'a, T> {
+    type Item = (&'static str, T);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.crawls.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use context::CrawlContext;
+    use crawler::CrawlerBuilder;
+    use downloader::Downloader;
+    use events::EventBus;
+    use futures::future::ok;
+    use futures::stream::empty;
+    use futures::{Async, Future};
+    use request::Request;
+    use response::Response;
+    use spider::{InternalRequestStream, ParseStream, RequestStream};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Sheduler without requests which remembers its global limit.
+    struct LimitSheduler(Rc<RefCell<Option<GlobalLimit>>>);
+
+    impl Stream for LimitSheduler {
+        type Item = Response;
+        type Error = Error;
+
+        fn poll(&mut self) -> Poll<Option<Response>, Error> {
+            Ok(Async::Ready(None))
+        }
+    }
+
+    impl Sheduler for LimitSheduler {
+        fn shedule(&mut self, _: InternalRequestStream) {}
+        fn shedule_fetches(&mut self, _: InternalRequestStream) {}
+        fn is_done(&self) -> bool {
+            true
+        }
+        fn set_downloader(&mut self, _: Downloader) {}
+        fn set_stats(&mut self, _: Stats) {}
+        fn set_events(&mut self, _: EventBus) {}
+        fn set_global_limit(&mut self, global_limit: GlobalLimit) {
+            *self.0.borrow_mut() = Some(global_limit);
+        }
+        fn take_rescheduled(&mut self) -> Vec<Request> {
+            Vec::new()
+        }
+        fn close(&mut self) {}
+        fn open(&mut self) {}
+    }
+
+    struct IdleSpider;
+
+    impl Spider for IdleSpider {
+        type Item = String;
+
+        fn name(&self) -> &'static str {
+            "idle"
+        }
+
+        fn start(&mut self, _: &CrawlContext) -> Box<Future<Item = RequestStream, Error = Error>> {
+            Box::new(ok(Box::new(empty()) as RequestStream))
+        }
+
+        fn parse(
+            &mut self,
+            _: Response,
+            _: &CrawlContext,
+        ) -> Box<Future<Item = ParseStream<String>, Error = Error> + Send> {
+            Box::new(ok(Box::new(empty()) as ParseStream<String>))
+        }
+    }
+
+    #[test]
+    fn global_limit_is_set_on_every_crawl() {
+        let limits = [Rc::new(RefCell::new(None)), Rc::new(RefCell::new(None))];
+        let crawlers: Vec<_> = limits
+            .iter()
+            .map(|limit| CrawlerBuilder::new(LimitSheduler(limit.clone())).build().unwrap())
+            .collect();
+        let process = CrawlerProcess::new()
+            .with_crawl(crawlers[0].crawl(IdleSpider))
+            .with_global_limit(2)
+            .with_crawl(crawlers[1].crawl(IdleSpider));
+        assert!(limits.iter().all(|limit| limit.borrow().is_some()));
+        assert_eq!(process.wait().count(), 0);
+    }
+}
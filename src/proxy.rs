@@ -0,0 +1,255 @@
+use client::{ClientConfig, HttpClient};
+use downloader::{DownloaderMiddleware, ErrorAction, RequestAction, ResponseAction};
+use failure::Error;
+use request::Request;
+use reqwest::StatusCode;
+use response::Response;
+use slog::Logger;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use tokio_core::reactor::Handle;
+use url::Url;
+
+/// Meta key with the proxy url of a request, an empty value means no proxy
+/// even if the crawler has a global one.
+pub const PROXY_META_KEY: &str = "proxy";
+
+/// Clients for every proxy used by the crawl.
+///
+/// `reqwest` configures proxies on the client, so a client is built with the crawler
+/// `ClientConfig` for every proxy url the first time it is used.
+pub(crate) struct ProxyClients {
+    handle: Option<Handle>,
+    default_proxy: Option<String>,
+    config: ClientConfig,
+    clients: RefCell<HashMap<String, HttpClient>>,
+}
+
+impl Default for ProxyClients {
+    fn default() -> Self {
+        ProxyClients::new(None, None, ClientConfig::default())
+    }
+}
+
+impl ProxyClients {
+    pub fn new(
+        handle: Option<Handle>,
+        default_proxy: Option<String>,
+        config: ClientConfig,
+    ) -> Self {
+        let clients = RefCell::new(HashMap::new());
+        ProxyClients {
+            handle,
+            default_proxy,
+            config,
+            clients,
+        }
+    }
+
+    /// Returns the client for the proxy of the request, `None` if it goes directly.
+    pub fn client_for(&self, request: &Request) -> Result<Option<HttpClient>, Error> {
+        let proxy = match request.meta().get(PROXY_META_KEY) {
+            Some(proxy) => proxy,
+            None => match self.default_proxy {
+                Some(ref proxy) => proxy,
+                None => return Ok(None),
+            },
+        };
+        if proxy.is_empty() {
+            return Ok(None);
+        }
+        if let Some(client) = self.clients.borrow().get(proxy) {
+            return Ok(Some(client.clone()));
+        }
+
+        let handle = self.handle.as_ref().ok_or_else(|| {
+            format_err!("proxies require a reactor handle, see CrawlerBuilder::with_handle")
+        })?;
+        let url: Url = proxy.parse()?;
+        let client = self.config.build_proxied(&url, handle)?;
+        self.clients
+            .borrow_mut()
+            .insert(proxy.to_owned(), client.clone());
+        Ok(Some(client))
+    }
+}
+
+struct PoolProxy {
+    url: String,
+    failures: u32,
+}
+
+/// Routes requests without a proxy through a pool of proxies in turn.
+///
+/// A proxy is marked as bad after `max_failures` failed downloads in a row
+/// and is not used anymore, failed requests are retried through another proxy.
+/// Requests are dropped when every proxy is bad.
+pub struct ProxyPoolMiddleware {
+    proxies: Vec<PoolProxy>,
+    next: usize,
+    max_failures: u32,
+    logger: Option<Logger>,
+}
+
+#[allow(dead_code)]
+impl ProxyPoolMiddleware {
+    pub fn new(proxies: Vec<String>, max_failures: u32) -> Self {
+        let proxies = proxies
+            .into_iter()
+            .map(|url| PoolProxy { url, failures: 0 })
+            .collect();
+        ProxyPoolMiddleware {
+            proxies,
+            next: 0,
+            max_failures,
+            logger: None,
+        }
+    }
+
+    pub fn with_logger(mut self, logger: Logger) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    fn next_good(&mut self) -> Option<String> {
+        for _ in 0..self.proxies.len() {
+            let proxy = &self.proxies[self.next];
+            self.next = (self.next + 1) % self.proxies.len();
+            if proxy.failures < self.max_failures {
+                return Some(proxy.url.clone());
+            }
+        }
+        None
+    }
+
+    fn pool_proxy(&mut self, request: &Request) -> Option<&mut PoolProxy> {
+        let url = request.meta().get(PROXY_META_KEY)?;
+        self.proxies.iter_mut().find(|proxy| &proxy.url == url)
+    }
+
+    fn failed(&mut self, request: &Request) {
+        let max_failures = self.max_failures;
+        let logger = self.logger.clone();
+        if let Some(proxy) = self.pool_proxy(request) {
+            proxy.failures += 1;
+            if proxy.failures == max_failures {
+                if let Some(ref logger) = logger {
+                    error!(logger, "proxy marked as bad"; "proxy" => &proxy.url);
+                }
+            }
+        }
+    }
+}
+
+impl DownloaderMiddleware for ProxyPoolMiddleware {
+    fn name(&self) -> &'static str {
+        "ProxyPoolMiddleware"
+    }
+
+    fn process_request(&mut self, mut request: Request) -> RequestAction {
+        if request.meta().contains_key(PROXY_META_KEY) {
+            return RequestAction::Continue(request);
+        }
+        match self.next_good() {
+            Some(proxy) => {
+                request.meta_mut().insert(PROXY_META_KEY.to_owned(), proxy);
+                RequestAction::Continue(request)
+            }
+            None => {
+                if let Some(ref logger) = self.logger {
+                    error!(logger, "no good proxies left, request dropped"; "request" => %request);
+                }
+                RequestAction::Drop
+            }
+        }
+    }
+
+    fn process_response(&mut self, response: Response) -> ResponseAction {
+        if response.status() == StatusCode::ProxyAuthenticationRequired {
+            self.failed(response.request());
+        } else if let Some(proxy) = self.pool_proxy(response.request()) {
+            proxy.failures = 0;
+        }
+        ResponseAction::Continue(response)
+    }
+
+    fn process_error(&mut self, request: &Request, error: Error) -> ErrorAction {
+        if self.pool_proxy(request).is_none() {
+            return ErrorAction::Fail(error);
+        }
+        self.failed(request);
+
+        if request.retries() as usize + 1 >= self.proxies.len() {
+            return ErrorAction::Fail(error);
+        }
+        if let Some(ref logger) = self.logger {
+            info!(logger, "retrying through another proxy"; "request" => %request, "error" => %error);
+        }
+        let mut retry = request.clone();
+        retry.meta_mut().remove(PROXY_META_KEY);
+        *retry.retries_mut() += 1;
+        *retry.dont_filter_mut() = true;
+        ErrorAction::Reschedule(retry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use downloader::{Downloaded, Downloader};
+    use reqwest::Method;
+    use reqwest::unstable::async::Client;
+    use test_server::{response, socks_proxy, TestServer};
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn requests_go_through_socks_proxy() {
+        let server = TestServer::start(vec![("/page", response("200 OK", &[], "proxied"))]);
+        let proxy = format!("socks5://user:secret@{}", socks_proxy(server.addr()));
+        let mut core = Core::new().unwrap();
+        let proxies = ProxyClients::new(Some(core.handle()), Some(proxy), ClientConfig::default());
+        let downloader = Downloader::default().with_proxies(proxies);
+        let client = Client::new(&core.handle());
+        let url = "http://example.com/page".parse().unwrap();
+        let downloaded = core.run(downloader.download(&client, Request::new(Method::Get, url)));
+        match downloaded.unwrap() {
+            Downloaded::Response(response) => {
+                assert_eq!(response.status(), StatusCode::Ok);
+                assert_eq!(&response.body()[..], b"proxied");
+            }
+            _ => panic!("expected a response"),
+        }
+        assert_eq!(server.requests(), vec!["/page".to_owned()]);
+    }
+
+    #[test]
+    fn failed_requests_are_retried_through_every_other_proxy() {
+        let proxies = vec!["http://127.0.0.1:1".to_owned(), "http://127.0.0.1:2".to_owned()];
+        let mut pool = ProxyPoolMiddleware::new(proxies, 10);
+        let mut request = Request::new(Method::Get, "http://example.com".parse().unwrap());
+        for retries in 0..2 {
+            request = match pool.process_request(request) {
+                RequestAction::Continue(request) => request,
+                _ => panic!("expected the request to go on"),
+            };
+            request = match pool.process_error(&request, format_err!("refused")) {
+                ErrorAction::Reschedule(retry) => retry,
+                ErrorAction::Fail(_) if retries == 1 => return,
+                _ => panic!("expected a retry"),
+            };
+            assert_eq!(request.retries(), retries + 1);
+            assert!(!request.meta().contains_key(PROXY_META_KEY));
+        }
+        panic!("expected the request to fail after trying every proxy");
+    }
+
+    #[test]
+    fn socks_proxy_clients_dont_follow_redirects() {
+        let core = Core::new().unwrap();
+        let config = ClientConfig::default().with_max_redirects(10);
+        let proxy = Some("socks5://127.0.0.1:1080".to_owned());
+        let proxies = ProxyClients::new(Some(core.handle()), proxy, config);
+        let request = Request::new(Method::Get, "http://example.com".parse().unwrap());
+        assert!(proxies.client_for(&request).is_err());
+    }
+}
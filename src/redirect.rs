@@ -0,0 +1,210 @@
+use downloader::{DownloaderMiddleware, ResponseAction};
+use request::Request;
+use reqwest::header::{ContentLength, ContentType, Cookie, Location};
+use reqwest::{Method, StatusCode};
+use response::Response;
+use slog::Logger;
+
+/// Follows redirects up to `max_redirects` hops.
+///
+/// The client must be built with `RedirectPolicy::none()`. Every hop is sheduled
+/// as a new request, so it goes through the dedup filter and the whole middleware chain,
+/// and the visited urls are recorded in `Response::redirect_chain`.
+///
+/// `process_response` hooks run in reverse order, so this middleware should be added
+/// before middlewares which need to see redirect responses, e.g. `CookiesMiddleware`.
+pub struct RedirectMiddleware {
+    max_redirects: usize,
+    logger: Option<Logger>,
+}
+
+#[allow(dead_code)]
+impl RedirectMiddleware {
+    pub fn new(max_redirects: usize) -> Self {
+        let logger = None;
+        RedirectMiddleware {
+            max_redirects,
+            logger,
+        }
+    }
+
+    pub fn with_logger(mut self, logger: Logger) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    fn redirect(&self, response: &Response) -> Option<Request> {
+        let location = response.headers().get::<Location>()?;
+        let url = match response.url().join(location) {
+            Ok(url) => url,
+            Err(e) => {
+                if let Some(ref logger) = self.logger {
+                    error!(logger, "wrong redirect location"; "response" => %response, "error" => %e);
+                }
+                return None;
+            }
+        };
+
+        let previous = response.request();
+        let mut request = previous.clone();
+        *request.url_mut() = url;
+        request.redirect_chain_mut().push(previous.url().clone());
+
+        // 301 and 302 are turned into GET for POST requests like browsers do,
+        // 303 always means GET, 307 and 308 keep the method and the body.
+        let status = response.status();
+        let to_get = match status {
+            StatusCode::SeeOther => *previous.method() != Method::Head,
+            StatusCode::MovedPermanently | StatusCode::Found => *previous.method() == Method::Post,
+            _ => false,
+        };
+        if to_get {
+            *request.method_mut() = Method::Get;
+            *request.body_mut() = None;
+            request.headers_mut().remove::<ContentType>();
+            request.headers_mut().remove::<ContentLength>();
+        }
+
+        // credentials are not sent to another origin, the cookie middleware
+        // sets the cookies of the new url again
+        if request.url().origin() != previous.url().origin() {
+            request.headers_mut().remove::<Cookie>();
+            request.headers_mut().remove_raw("Authorization");
+        }
+        Some(request)
+    }
+}
+
+fn is_redirect(status: StatusCode) -> bool {
+    match status {
+        StatusCode::MovedPermanently
+        | StatusCode::Found
+        | StatusCode::SeeOther
+        | StatusCode::TemporaryRedirect
+        | StatusCode::PermanentRedirect => true,
+        _ => false,
+    }
+}
+
+impl DownloaderMiddleware for RedirectMiddleware {
+    fn name(&self) -> &'static str {
+        "RedirectMiddleware"
+    }
+
+    fn process_response(&mut self, response: Response) -> ResponseAction {
+        if !is_redirect(response.status()) {
+            return ResponseAction::Continue(response);
+        }
+        if response.redirect_chain().len() >= self.max_redirects {
+            if let Some(ref logger) = self.logger {
+                info!(logger, "too many redirects"; "response" => %response);
+            }
+            return ResponseAction::Drop;
+        }
+        match self.redirect(&response) {
+            Some(request) => {
+                if let Some(ref logger) = self.logger {
+                    debug!(logger, "redirected"; "response" => %response, "request" => %request);
+                }
+                ResponseAction::Reschedule(request)
+            }
+            None => ResponseAction::Continue(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use reqwest::header::Headers;
+
+    fn post(url: &str) -> Request {
+        let mut request = Request::new(Method::Post, url.parse().unwrap());
+        *request.body_mut() = Some("form".into());
+        request.headers_mut().set(ContentType::form_url_encoded());
+        request
+    }
+
+    fn redirected(
+        middleware: &mut RedirectMiddleware,
+        request: Request,
+        status: StatusCode,
+        location: &str,
+    ) -> ResponseAction {
+        let mut headers = Headers::new();
+        headers.set(Location::new(location.to_owned()));
+        let url = request.url().clone();
+        middleware.process_response(Response::new(url, status, headers, Bytes::new(), request))
+    }
+
+    fn rescheduled(action: ResponseAction) -> Request {
+        match action {
+            ResponseAction::Reschedule(request) => request,
+            _ => panic!("redirect was not followed"),
+        }
+    }
+
+    #[test]
+    fn see_other_and_moved_post_become_get() {
+        let mut middleware = RedirectMiddleware::new(10);
+        for status in &[StatusCode::MovedPermanently, StatusCode::Found, StatusCode::SeeOther] {
+            let request = post("http://example.com/form");
+            let action = redirected(&mut middleware, request, *status, "/done");
+            let request = rescheduled(action);
+            assert_eq!(*request.method(), Method::Get);
+            assert_eq!(request.url().as_str(), "http://example.com/done");
+            assert!(request.body().is_none());
+            assert!(!request.headers().has::<ContentType>());
+        }
+    }
+
+    #[test]
+    fn temporary_and_permanent_redirects_keep_method_and_body() {
+        let mut middleware = RedirectMiddleware::new(10);
+        for status in &[StatusCode::TemporaryRedirect, StatusCode::PermanentRedirect] {
+            let request = post("http://example.com/form");
+            let action = redirected(&mut middleware, request, *status, "/again");
+            let request = rescheduled(action);
+            assert_eq!(*request.method(), Method::Post);
+            assert_eq!(request.body().map(|body| body.as_ref().to_vec()), Some(b"form".to_vec()));
+            assert!(request.headers().has::<ContentType>());
+        }
+    }
+
+    #[test]
+    fn chain_is_recorded_up_to_max_redirects() {
+        let mut middleware = RedirectMiddleware::new(2);
+        let first = Request::new(Method::Get, "http://example.com/a".parse().unwrap());
+        let second = rescheduled(redirected(&mut middleware, first, StatusCode::Found, "/b"));
+        let third = rescheduled(redirected(&mut middleware, second, StatusCode::Found, "/c"));
+        let chain: Vec<&str> = third.redirect_chain().iter().map(|url| url.path()).collect();
+        assert_eq!(chain, vec!["/a", "/b"]);
+        assert_eq!(third.url().path(), "/c");
+        match redirected(&mut middleware, third, StatusCode::Found, "/d") {
+            ResponseAction::Drop => {}
+            _ => panic!("redirect over the limit was followed"),
+        }
+    }
+
+    #[test]
+    fn credentials_are_stripped_across_origins() {
+        let mut middleware = RedirectMiddleware::new(10);
+        let request = || {
+            let mut request = Request::new(Method::Get, "http://example.com/a".parse().unwrap());
+            request.headers_mut().set_raw("Cookie", "session=1");
+            request.headers_mut().set_raw("Authorization", "Basic dXNlcjpwYXNz");
+            request
+        };
+        let same = rescheduled(redirected(&mut middleware, request(), StatusCode::Found, "/b"));
+        assert!(same.headers().get_raw("Cookie").is_some());
+        assert!(same.headers().get_raw("Authorization").is_some());
+        let others = ["http://other.com/b", "https://example.com/b", "http://example.com:8080/b"];
+        for location in &others {
+            let action = redirected(&mut middleware, request(), StatusCode::Found, location);
+            let other = rescheduled(action);
+            assert!(other.headers().get_raw("Cookie").is_none());
+            assert!(other.headers().get_raw("Authorization").is_none());
+        }
+    }
+}
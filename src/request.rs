@@ -1,14 +1,25 @@
+use base64;
 use body::Body;
+use failure::Error;
 use reqwest::header::Headers;
 use reqwest::unstable::async;
 use reqwest::Method;
+use std::collections::BTreeMap;
 use std::convert::From;
 use url::Url;
 
+pub type Meta = BTreeMap<String, String>;
+
 // wrapper around reqwest::Request
 pub struct Request {
     inner: async::Request,
     body: Option<Body>,
+    meta: Meta,
+    depth: u32,
+    priority: i32,
+    retries: u32,
+    redirect_chain: Vec<Url>,
+    dont_filter: bool,
 }
 
 impl ::fmt::Display for Request {
@@ -23,7 +34,22 @@ impl Request {
     pub fn new(method: Method, url: Url) -> Self {
         let inner = async::Request::new(method, url);
         let body = None;
-        Request { inner, body }
+        let meta = Meta::new();
+        let depth = 0;
+        let priority = 0;
+        let retries = 0;
+        let redirect_chain = Vec::new();
+        let dont_filter = false;
+        Request {
+            inner,
+            body,
+            meta,
+            depth,
+            priority,
+            retries,
+            redirect_chain,
+            dont_filter,
+        }
     }
 
     /// Get the method.
@@ -73,6 +99,104 @@ impl Request {
     pub fn body_mut(&mut self) -> &mut Option<Body> {
         &mut self.body
     }
+
+    /// Get the meta, arbitrary values which travel with the request
+    /// and can be read by middlewares and by the spider from the response.
+    #[inline]
+    pub fn meta(&self) -> &Meta {
+        &self.meta
+    }
+
+    /// Get a mutable reference to the meta.
+    #[inline]
+    pub fn meta_mut(&mut self) -> &mut Meta {
+        &mut self.meta
+    }
+
+    /// Get the number of responses which led to this request, start requests have depth 0.
+    #[inline]
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// Get a mutable reference to the depth.
+    #[inline]
+    pub fn depth_mut(&mut self) -> &mut u32 {
+        &mut self.depth
+    }
+
+    /// Get the priority, requests with a higher priority should go first.
+    /// The built-in shedulers don't use it yet.
+    #[inline]
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// Get a mutable reference to the priority.
+    #[inline]
+    pub fn priority_mut(&mut self) -> &mut i32 {
+        &mut self.priority
+    }
+
+    /// Get the number of times this request was retried.
+    #[inline]
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Get a mutable reference to the retries.
+    #[inline]
+    pub fn retries_mut(&mut self) -> &mut u32 {
+        &mut self.retries
+    }
+
+    /// Get the urls which redirected to this request, the original url goes first.
+    #[inline]
+    pub fn redirect_chain(&self) -> &[Url] {
+        &self.redirect_chain
+    }
+
+    /// Get a mutable reference to the redirect chain.
+    #[inline]
+    pub fn redirect_chain_mut(&mut self) -> &mut Vec<Url> {
+        &mut self.redirect_chain
+    }
+
+    /// Get whether the request skips the duplicate filter, e.g. when it is retried.
+    #[inline]
+    pub fn dont_filter(&self) -> bool {
+        self.dont_filter
+    }
+
+    /// Get a mutable reference to the dont filter flag.
+    #[inline]
+    pub fn dont_filter_mut(&mut self) -> &mut bool {
+        &mut self.dont_filter
+    }
+}
+
+impl Clone for Request {
+    fn clone(&self) -> Request {
+        let mut inner = async::Request::new(self.method().clone(), self.url().clone());
+        *inner.headers_mut() = self.headers().clone();
+        let body = self.body.clone();
+        let meta = self.meta.clone();
+        let depth = self.depth;
+        let priority = self.priority;
+        let retries = self.retries;
+        let redirect_chain = self.redirect_chain.clone();
+        let dont_filter = self.dont_filter;
+        Request {
+            inner,
+            body,
+            meta,
+            depth,
+            priority,
+            retries,
+            redirect_chain,
+            dont_filter,
+        }
+    }
 }
 
 impl From<Request> for async::Request {
@@ -85,3 +209,124 @@ impl From<Request> for async::Request {
         request
     }
 }
+
+/// Serializable form of a `Request`, e.g. for disk queues.
+///
+/// Header values which are not UTF-8 are stored lossily, everything else survives
+/// the round trip.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RequestSpec {
+    #[serde(default = "default_method")]
+    pub method: String,
+    pub url: String,
+    /// Name and value pairs, a header with several values has a pair for each.
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// Base64 encoded.
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub meta: Meta,
+    #[serde(default)]
+    pub depth: u32,
+    #[serde(default)]
+    pub retries: u32,
+    #[serde(default)]
+    pub redirect_chain: Vec<String>,
+    #[serde(default)]
+    pub dont_filter: bool,
+}
+
+fn default_method() -> String {
+    Method::Get.to_string()
+}
+
+#[allow(dead_code)]
+impl RequestSpec {
+    pub fn into_request(self) -> Result<Request, Error> {
+        let method: Method = self.method.parse()?;
+        let url: Url = self.url.parse()?;
+        let mut request = Request::new(method, url);
+        for (name, value) in self.headers {
+            request.headers_mut().append_raw(name, value);
+        }
+        if let Some(body) = self.body {
+            *request.body_mut() = Some(Body::from(base64::decode(&body)?));
+        }
+        *request.priority_mut() = self.priority;
+        *request.meta_mut() = self.meta;
+        *request.depth_mut() = self.depth;
+        *request.retries_mut() = self.retries;
+        for url in self.redirect_chain {
+            request.redirect_chain_mut().push(url.parse()?);
+        }
+        *request.dont_filter_mut() = self.dont_filter;
+        Ok(request)
+    }
+}
+
+impl<'a> From<&'a Request> for RequestSpec {
+    fn from(request: &'a Request) -> RequestSpec {
+        let mut headers = Vec::new();
+        for header in request.headers().iter() {
+            for value in header.raw().iter() {
+                let value = String::from_utf8_lossy(value).into_owned();
+                headers.push((header.name().to_owned(), value));
+            }
+        }
+        RequestSpec {
+            method: request.method().to_string(),
+            url: request.url().to_string(),
+            headers,
+            body: request.body().map(|body| base64::encode(body.as_ref())),
+            priority: request.priority(),
+            meta: request.meta().clone(),
+            depth: request.depth(),
+            retries: request.retries(),
+            redirect_chain: request.redirect_chain().iter().map(Url::to_string).collect(),
+            dont_filter: request.dont_filter(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn spec_round_trip() {
+        let mut request = Request::new(Method::Post, "http://example.com/b?q=1".parse().unwrap());
+        request.headers_mut().append_raw("Accept", "text/html");
+        request.headers_mut().append_raw("Accept", "*/*");
+        request.headers_mut().set_raw("X-Token", "secret");
+        *request.body_mut() = Some(Body::from(vec![0u8, 159, 146, 150, 255]));
+        request.meta_mut().insert("page".to_owned(), "2".to_owned());
+        *request.priority_mut() = -3;
+        *request.depth_mut() = 4;
+        *request.retries_mut() = 1;
+        request.redirect_chain_mut().push("http://example.com/".parse().unwrap());
+        request.redirect_chain_mut().push("http://example.com/a".parse().unwrap());
+        *request.dont_filter_mut() = true;
+
+        let json = serde_json::to_string(&RequestSpec::from(&request)).unwrap();
+        let spec: RequestSpec = serde_json::from_str(&json).unwrap();
+        let copy = spec.into_request().unwrap();
+
+        assert_eq!(*copy.method(), Method::Post);
+        assert_eq!(copy.url(), request.url());
+        let accept: Vec<_> = copy.headers().get_raw("Accept").unwrap().iter().collect();
+        assert_eq!(accept, vec![&b"text/html"[..], &b"*/*"[..]]);
+        assert_eq!(copy.headers().get_raw("X-Token").unwrap().one(), Some(&b"secret"[..]));
+        assert_eq!(copy.headers().len(), 2);
+        assert_eq!(copy.body().unwrap().as_ref(), &[0u8, 159, 146, 150, 255][..]);
+        assert_eq!(copy.meta(), request.meta());
+        assert_eq!(copy.priority(), -3);
+        assert_eq!(copy.depth(), 4);
+        assert_eq!(copy.retries(), 1);
+        assert_eq!(copy.redirect_chain(), request.redirect_chain());
+        assert!(copy.dont_filter());
+    }
+}
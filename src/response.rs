@@ -0,0 +1,101 @@
+use bytes::Bytes;
+use request::Request;
+use reqwest::header::Headers;
+use reqwest::StatusCode;
+use std::borrow::Cow;
+use url::Url;
+
+/// A downloaded response with its body fully read.
+///
+/// Unlike `reqwest::unstable::async::Response` it can be constructed outside
+/// of the client, so middlewares are able to replace or short-circuit responses.
+#[derive(Clone)]
+pub struct Response {
+    url: Url,
+    status: StatusCode,
+    headers: Headers,
+    body: Bytes,
+    request: Request,
+}
+
+impl ::fmt::Display for Response {
+    fn fmt(&self, f: &mut ::fmt::Formatter) -> ::fmt::Result {
+        write!(f, "Response({}, Url({}))", self.status, self.url)
+    }
+}
+
+#[allow(dead_code)]
+impl Response {
+    /// Constructs a new response to the given request.
+    #[inline]
+    pub fn new(url: Url, status: StatusCode, headers: Headers, body: Bytes, request: Request) -> Self {
+        Response {
+            url,
+            status,
+            headers,
+            body,
+            request,
+        }
+    }
+
+    /// Get the final url of the response.
+    #[inline]
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Get the status code.
+    #[inline]
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Get a mutable reference to the status code.
+    #[inline]
+    pub fn status_mut(&mut self) -> &mut StatusCode {
+        &mut self.status
+    }
+
+    /// Get the headers.
+    #[inline]
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Get a mutable reference to the headers.
+    #[inline]
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    /// Get the body.
+    #[inline]
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    /// Get a mutable reference to the body.
+    #[inline]
+    pub fn body_mut(&mut self) -> &mut Bytes {
+        &mut self.body
+    }
+
+    /// Get the body decoded as utf-8, invalid sequences are replaced.
+    #[inline]
+    pub fn text(&self) -> Cow<str> {
+        String::from_utf8_lossy(&self.body)
+    }
+
+    /// Get the request which produced this response.
+    #[inline]
+    pub fn request(&self) -> &Request {
+        &self.request
+    }
+
+    /// Get the urls which were redirected before reaching this response,
+    /// the originally requested url goes first.
+    #[inline]
+    pub fn redirect_chain(&self) -> &[Url] {
+        self.request.redirect_chain()
+    }
+}
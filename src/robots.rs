@@ -0,0 +1,620 @@
+use client::HttpClient;
+use downloader::{execute, DownloadLimits, DownloaderMiddleware, RequestAction};
+use failure::Error;
+use futures::future::{loop_fn, Loop, Shared};
+use futures::Future;
+use request::Request;
+use reqwest::header::{Location, UserAgent};
+use reqwest::unstable::async::Client;
+use reqwest::Method;
+use settings::seconds;
+use sheduler::HostDelays;
+use slog::Logger;
+use stats::Stats;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio_core::reactor::Handle;
+use url::Url;
+
+/// Redirects followed when fetching robots.txt, e.g. from http to https.
+const MAX_ROBOTS_REDIRECTS: usize = 5;
+
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+impl Rule {
+    /// Matches the pattern against the path, `*` matches any sequence
+    /// and a trailing `$` anchors the pattern at the end of the path.
+    fn matches(&self, path: &str) -> bool {
+        let anchored = self.pattern.ends_with('$');
+        let pattern = if anchored {
+            &self.pattern[..self.pattern.len() - 1]
+        } else {
+            &self.pattern[..]
+        };
+        let mut parts = pattern.split('*');
+        let first = parts.next().unwrap_or("");
+        if !path.starts_with(first) {
+            return false;
+        }
+        let mut rest = &path[first.len()..];
+        let parts: Vec<&str> = parts.collect();
+        for (i, part) in parts.iter().enumerate() {
+            let last = i + 1 == parts.len();
+            if last && anchored {
+                return rest.ends_with(part);
+            }
+            match rest.find(part) {
+                Some(pos) => rest = &rest[pos + part.len()..],
+                None => return false,
+            }
+        }
+        !anchored || rest.is_empty()
+    }
+}
+
+/// Rules of a robots.txt file which apply to one user agent.
+pub struct RobotsTxt {
+    rules: Vec<Rule>,
+    crawl_delay: Option<Duration>,
+}
+
+struct Group {
+    agents: Vec<String>,
+    rules: Vec<Rule>,
+    crawl_delay: Option<Duration>,
+}
+
+#[allow(dead_code)]
+impl RobotsTxt {
+    pub fn allow_all() -> Self {
+        RobotsTxt {
+            rules: Vec::new(),
+            crawl_delay: None,
+        }
+    }
+
+    pub fn disallow_all() -> Self {
+        let rule = Rule {
+            allow: false,
+            pattern: "/".to_owned(),
+        };
+        RobotsTxt {
+            rules: vec![rule],
+            crawl_delay: None,
+        }
+    }
+
+    /// Parses robots.txt and keeps the group which matches `user_agent` best,
+    /// i.e. with the longest agent contained in its product token,
+    /// falling back to the `*` group.
+    pub fn parse(text: &str, user_agent: &str) -> Self {
+        let mut groups: Vec<Group> = Vec::new();
+        let mut in_agents = false;
+        for line in text.lines() {
+            let line = match line.find('#') {
+                Some(pos) => &line[..pos],
+                None => line,
+            };
+            let mut split = line.splitn(2, ':');
+            let key = split.next().unwrap_or("").trim().to_lowercase();
+            let value = match split.next() {
+                Some(value) => value.trim(),
+                None => continue,
+            };
+            if key == "user-agent" {
+                if !in_agents {
+                    groups.push(Group {
+                        agents: Vec::new(),
+                        rules: Vec::new(),
+                        crawl_delay: None,
+                    });
+                    in_agents = true;
+                }
+                if let Some(group) = groups.last_mut() {
+                    group.agents.push(value.to_lowercase());
+                }
+                continue;
+            }
+            in_agents = false;
+            let group = match groups.last_mut() {
+                Some(group) => group,
+                None => continue,
+            };
+            match key.as_str() {
+                "allow" | "disallow" if !value.is_empty() => group.rules.push(Rule {
+                    allow: key == "allow",
+                    pattern: value.to_owned(),
+                }),
+                "crawl-delay" => {
+                    if let Ok(delay) = value.parse() {
+                        group.crawl_delay = Some(seconds(delay));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let user_agent = user_agent.to_lowercase();
+        let product = user_agent.split('/').next().unwrap_or("");
+        let matched = |group: &Group| {
+            group
+                .agents
+                .iter()
+                .filter(|agent| *agent != "*" && product.contains(agent.as_str()))
+                .map(|agent| agent.len())
+                .max()
+        };
+        let mut best: Option<(usize, usize)> = None;
+        for (position, group) in groups.iter().enumerate() {
+            if let Some(len) = matched(group) {
+                if best.map_or(true, |(_, best_len)| len > best_len) {
+                    best = Some((position, len));
+                }
+            }
+        }
+        let position = best.map(|(position, _)| position)
+            .or_else(|| {
+                groups
+                    .iter()
+                    .position(|group| group.agents.iter().any(|agent| agent == "*"))
+            });
+        match position {
+            Some(position) => {
+                let group = groups.swap_remove(position);
+                RobotsTxt {
+                    rules: group.rules,
+                    crawl_delay: group.crawl_delay,
+                }
+            }
+            None => Self::allow_all(),
+        }
+    }
+
+    /// The longest matching rule wins, allow wins a tie.
+    pub fn allows(&self, url: &Url) -> bool {
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_owned(),
+        };
+        let mut best: Option<&Rule> = None;
+        for rule in self.rules.iter().filter(|rule| rule.matches(&path)) {
+            best = match best {
+                Some(best)
+                    if best.pattern.len() > rule.pattern.len()
+                        || (best.pattern.len() == rule.pattern.len() && best.allow) =>
+                {
+                    Some(best)
+                }
+                _ => Some(rule),
+            };
+        }
+        best.map(|rule| rule.allow).unwrap_or(true)
+    }
+
+    pub fn crawl_delay(&self) -> Option<Duration> {
+        self.crawl_delay
+    }
+}
+
+/// Outcome of fetching robots.txt of an origin.
+enum RobotsFile {
+    Found(String),
+    AllowAll,
+    DisallowAll,
+}
+
+impl RobotsFile {
+    fn rules(&self, user_agent: &str) -> RobotsTxt {
+        match *self {
+            RobotsFile::Found(ref text) => RobotsTxt::parse(text, user_agent),
+            RobotsFile::AllowAll => RobotsTxt::allow_all(),
+            RobotsFile::DisallowAll => RobotsTxt::disallow_all(),
+        }
+    }
+}
+
+type RobotsFuture = Shared<Box<Future<Item = RobotsFile, Error = !>>>;
+
+/// Parsed rules by origin and user agent.
+type RulesCache = Rc<RefCell<HashMap<(String, String), Rc<RobotsTxt>>>>;
+
+/// Fetches robots.txt before the first request to an origin and drops requests it disallows.
+///
+/// Rules are matched against the `User-Agent` the request is sent with, `user_agent` is used
+/// for requests without one and for `Crawl-delay`, which is passed to the sheduler through
+/// `HostDelays`. Redirects of robots.txt are followed even if the client doesn't follow
+/// redirects. A missing robots.txt or a failed download allows everything, a server error
+/// disallows the origin for the rest of the crawl.
+pub struct RobotsTxtMiddleware {
+    client: Client,
+    user_agent: String,
+    host_delays: Option<HostDelays>,
+    limits: DownloadLimits,
+    handle: Option<Handle>,
+    stats: Option<Stats>,
+    cache: HashMap<String, RobotsFuture>,
+    rules: RulesCache,
+    logger: Option<Logger>,
+}
+
+#[allow(dead_code)]
+impl RobotsTxtMiddleware {
+    pub fn new(client: Client, user_agent: &str) -> Self {
+        RobotsTxtMiddleware {
+            client,
+            user_agent: user_agent.to_owned(),
+            host_delays: None,
+            limits: DownloadLimits::default(),
+            handle: None,
+            stats: None,
+            cache: HashMap::new(),
+            rules: Rc::new(RefCell::new(HashMap::new())),
+            logger: None,
+        }
+    }
+
+    /// Size limits and timeouts of robots.txt downloads, timeouts are only enforced
+    /// with a reactor handle.
+    pub fn with_limits(mut self, limits: DownloadLimits, handle: Option<Handle>) -> Self {
+        self.limits = limits;
+        self.handle = handle;
+        self
+    }
+
+    /// Counts robots.txt responses and failed downloads, e.g. in the stats of the crawl.
+    pub fn with_stats(mut self, stats: Stats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    pub fn with_host_delays(mut self, host_delays: HostDelays) -> Self {
+        self.host_delays = Some(host_delays);
+        self
+    }
+
+    pub fn with_logger(mut self, logger: Logger) -> Self {
+        self.logger = Some(logger);
+        self
+    }
+
+    fn fetch(&self, url: &Url) -> RobotsFuture {
+        let robots_url = url.join("/robots.txt").expect("robots.txt url");
+        let client = HttpClient::Reqwest(self.client.clone());
+        let user_agent = self.user_agent.clone();
+        let limits = self.limits.clone();
+        let handle = self.handle.clone();
+        let stats = self.stats.clone();
+        let logger = self.logger.clone();
+        let fetched = loop_fn((robots_url, 0), move |(url, redirects)| {
+            let mut request = Request::new(Method::Get, url.clone());
+            request
+                .headers_mut()
+                .set(UserAgent::new(user_agent.clone()));
+            let fut = execute(&client, request, limits.clone(), handle.clone(), logger.clone());
+            let stats = stats.clone();
+            fut.then(move |result| {
+                let (_, status, headers, body) = match result {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        if let Some(ref stats) = stats {
+                            stats.request_failed();
+                        }
+                        return Err(e);
+                    }
+                };
+                if let Some(ref stats) = stats {
+                    stats.response_received(status, body.len());
+                }
+                let location = headers
+                    .get::<Location>()
+                    .and_then(|location| url.join(location).ok());
+                match location {
+                    Some(location)
+                        if status.is_redirection() && redirects < MAX_ROBOTS_REDIRECTS =>
+                    {
+                        Ok(Loop::Continue((location, redirects + 1)))
+                    }
+                    _ => Ok(Loop::Break((status, body))),
+                }
+            })
+        });
+
+        let host = url.host_str().unwrap_or("").to_owned();
+        let user_agent = self.user_agent.clone();
+        let host_delays = self.host_delays.clone();
+        let logger = self.logger.clone();
+        let fut = fetched.then(move |result| {
+            let file = match result {
+                Ok((status, body)) if status.is_success() => {
+                    RobotsFile::Found(String::from_utf8_lossy(&body).into_owned())
+                }
+                Ok((status, _)) if status.is_server_error() => {
+                    if let Some(ref logger) = logger {
+                        warn!(logger, "robots.txt is unavailable, disallowing the host";
+                              "host" => &host, "status" => %status);
+                    }
+                    RobotsFile::DisallowAll
+                }
+                Ok(_) => RobotsFile::AllowAll,
+                Err(e) => {
+                    if let Some(ref logger) = logger {
+                        error!(logger, "failed to fetch robots.txt"; "host" => &host, "error" => %e);
+                    }
+                    RobotsFile::AllowAll
+                }
+            };
+            let delay = file.rules(&user_agent).crawl_delay();
+            if let (Some(delay), Some(host_delays)) = (delay, host_delays) {
+                host_delays.set_delay(&host, delay);
+            }
+            Ok(file)
+        });
+        (Box::new(fut) as Box<Future<Item = RobotsFile, Error = !>>).shared()
+    }
+}
+
+fn check(robots: &RobotsTxt, request: Request, logger: &Option<Logger>) -> RequestAction {
+    if robots.allows(request.url()) {
+        return RequestAction::Continue(request);
+    }
+    if let Some(ref logger) = *logger {
+        info!(logger, "request forbidden by robots.txt"; "request" => %request);
+    }
+    RequestAction::Drop
+}
+
+impl DownloaderMiddleware for RobotsTxtMiddleware {
+    fn name(&self) -> &'static str {
+        "RobotsTxtMiddleware"
+    }
+
+    fn process_request(&mut self, request: Request) -> RequestAction {
+        if request.url().path() == "/robots.txt" {
+            return RequestAction::Continue(request);
+        }
+        let user_agent = match request.headers().get::<UserAgent>() {
+            Some(user_agent) => user_agent.to_string(),
+            None => self.user_agent.clone(),
+        };
+        let origin = request.url().origin().ascii_serialization();
+        let key = (origin.clone(), user_agent);
+        let parsed = self.rules.borrow().get(&key).cloned();
+        if let Some(robots) = parsed {
+            return check(&robots, request, &self.logger);
+        }
+        let cached = self.cache.get(&origin).cloned();
+        let file = match cached {
+            Some(file) => file,
+            None => {
+                let file = self.fetch(request.url());
+                self.cache.insert(origin, file.clone());
+                file
+            }
+        };
+        // the file is parsed once for every user agent it is matched against
+        let rules = self.rules.clone();
+        let logger = self.logger.clone();
+        let fut = file
+            .map_err(|_| -> Error { unreachable!("robots.txt future can't fail") })
+            .map(move |file| {
+                let user_agent = key.1.clone();
+                let robots = rules
+                    .borrow_mut()
+                    .entry(key)
+                    .or_insert_with(|| Rc::new(file.rules(&user_agent)))
+                    .clone();
+                check(&robots, request, &logger)
+            });
+        RequestAction::Pending(Box::new(fut))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{response, stalling_server, TestServer};
+    use tokio_core::reactor::Core;
+
+    fn rule(pattern: &str) -> Rule {
+        Rule {
+            allow: true,
+            pattern: pattern.to_owned(),
+        }
+    }
+
+    fn url(path: &str) -> Url {
+        format!("http://example.com{}", path).parse().unwrap()
+    }
+
+    #[test]
+    fn rule_matches_prefix_wildcards_and_anchor() {
+        assert!(rule("/private").matches("/private/page"));
+        assert!(!rule("/private").matches("/public"));
+        assert!(rule("/*.php").matches("/dir/index.php?x=1"));
+        assert!(!rule("/*.php$").matches("/index.php?x=1"));
+        assert!(rule("/*.php$").matches("/index.php"));
+        assert!(rule("/a*b*c").matches("/a-b-c-d"));
+        assert!(!rule("/a*c*b").matches("/a-b-c"));
+        assert!(rule("/exact$").matches("/exact"));
+        assert!(!rule("/exact$").matches("/exact/more"));
+    }
+
+    #[test]
+    fn longest_rule_wins_and_allow_wins_a_tie() {
+        let text = "User-agent: *\nDisallow: /shop\nAllow: /shop/public\nAllow: /same\nDisallow: /same\n";
+        let robots = RobotsTxt::parse(text, "bot");
+        assert!(!robots.allows(&url("/shop/cart")));
+        assert!(robots.allows(&url("/shop/public/item")));
+        assert!(robots.allows(&url("/same")));
+        assert!(robots.allows(&url("/other")));
+    }
+
+    #[test]
+    fn most_specific_agent_group_is_used() {
+        let text = "\
+User-agent: *
+Disallow: /all
+
+User-agent: bot
+Disallow: /bot
+Crawl-delay: 1
+
+User-agent: superbot
+Disallow: /superbot
+Crawl-delay: 2.5
+";
+        let robots = RobotsTxt::parse(text, "SuperBot/1.0");
+        assert!(!robots.allows(&url("/superbot")));
+        assert!(robots.allows(&url("/bot")));
+        assert!(robots.allows(&url("/all")));
+        assert_eq!(robots.crawl_delay(), Some(Duration::from_millis(2500)));
+
+        let robots = RobotsTxt::parse(text, "bot/2.0");
+        assert!(!robots.allows(&url("/bot")));
+        assert!(robots.allows(&url("/superbot")));
+
+        let robots = RobotsTxt::parse(text, "other");
+        assert!(!robots.allows(&url("/all")));
+        assert_eq!(robots.crawl_delay(), None);
+    }
+
+    #[test]
+    fn agents_comments_and_empty_disallow() {
+        let text = "# comment\nUser-agent: a\nUser-agent: b # both\nDisallow:\n\nUser-agent: *\nDisallow: /\n";
+        assert!(RobotsTxt::parse(text, "b").allows(&url("/page")));
+        assert!(!RobotsTxt::parse(text, "c").allows(&url("/page")));
+        assert!(RobotsTxt::parse("", "c").allows(&url("/page")));
+    }
+
+    fn allowed(core: &mut Core, robots: &mut RobotsTxtMiddleware, url: Url) -> bool {
+        process(core, robots, Request::new(Method::Get, url))
+    }
+
+    fn process(core: &mut Core, robots: &mut RobotsTxtMiddleware, request: Request) -> bool {
+        let action = match robots.process_request(request) {
+            RequestAction::Pending(fut) => core.run(fut).unwrap(),
+            action => action,
+        };
+        match action {
+            RequestAction::Continue(_) => true,
+            RequestAction::Drop => false,
+            _ => panic!("unexpected robots.txt action"),
+        }
+    }
+
+    #[test]
+    fn middleware_fetches_robots_once_and_sets_crawl_delay() {
+        let robots_txt = "User-agent: *\nDisallow: /private\nCrawl-delay: 3\n";
+        let server = TestServer::start(vec![
+            ("/robots.txt", response("200 OK", &[], robots_txt)),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let host_delays = HostDelays::new(core.handle());
+        let mut robots = RobotsTxtMiddleware::new(client, "bot").with_host_delays(host_delays.clone());
+
+        assert!(allowed(&mut core, &mut robots, server.url("/public")));
+        assert!(!allowed(&mut core, &mut robots, server.url("/private/page")));
+        assert_eq!(server.requests(), vec!["/robots.txt".to_owned()]);
+        assert_eq!(host_delays.delay("127.0.0.1"), Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn middleware_follows_robots_redirects() {
+        let other = TestServer::start_on("127.0.0.2", vec![
+            ("/robots.txt", response("200 OK", &[], "User-agent: *\nDisallow: /private\n")),
+        ]);
+        let location = other.url("/robots.txt").to_string();
+        let server = TestServer::start(vec![
+            ("/robots.txt", response("301 Moved Permanently", &[("Location", "/moved")], "")),
+            ("/moved", response("302 Found", &[("Location", &location)], "")),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::builder()
+            .redirect(::reqwest::RedirectPolicy::none())
+            .build(&core.handle())
+            .unwrap();
+        let mut robots = RobotsTxtMiddleware::new(client, "bot");
+        assert!(!allowed(&mut core, &mut robots, server.url("/private")));
+        assert_eq!(server.requests(), vec!["/robots.txt", "/moved"]);
+    }
+
+    #[test]
+    fn middleware_matches_request_user_agent() {
+        let robots_txt = "User-agent: *\nDisallow: /\n\nUser-agent: superbot\nDisallow: /private\n";
+        let server = TestServer::start(vec![
+            ("/robots.txt", response("200 OK", &[], robots_txt)),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let mut robots = RobotsTxtMiddleware::new(client, "bot");
+        let mut request = Request::new(Method::Get, server.url("/page"));
+        request.headers_mut().set(UserAgent::new("SuperBot/1.0"));
+        assert!(process(&mut core, &mut robots, request));
+        assert!(!allowed(&mut core, &mut robots, server.url("/page")));
+        // the file is cached by origin, not by user agent
+        assert_eq!(server.requests(), vec!["/robots.txt"]);
+    }
+
+    #[test]
+    fn middleware_disallows_on_server_errors() {
+        let server = TestServer::start(vec![
+            ("/robots.txt", response("503 Service Unavailable", &[], "")),
+        ]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let mut robots = RobotsTxtMiddleware::new(client, "bot");
+        assert!(!allowed(&mut core, &mut robots, server.url("/page")));
+        assert!(!allowed(&mut core, &mut robots, server.url("/")));
+    }
+
+    #[test]
+    fn middleware_applies_limits_and_counts_stats() {
+        let robots_txt = "User-agent: *\nDisallow: /private\n";
+        let server = TestServer::start(vec![
+            ("/robots.txt", response("200 OK", &[], robots_txt)),
+        ]);
+        let stalled = stalling_server("");
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let handle = core.handle();
+        let stats = Stats::new();
+        let new_robots = |max_size| {
+            let limits = DownloadLimits {
+                max_size: Some(max_size),
+                connect_timeout: Some(Duration::from_millis(100)),
+                ..DownloadLimits::default()
+            };
+            RobotsTxtMiddleware::new(client.clone(), "bot")
+                .with_limits(limits, Some(handle.clone()))
+                .with_stats(stats.clone())
+        };
+
+        let mut robots = new_robots(1000);
+        assert!(!allowed(&mut core, &mut robots, server.url("/private")));
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.pages(), snapshot.errors), (1, 0));
+
+        // a failed download allows everything
+        let mut robots = new_robots(10);
+        assert!(allowed(&mut core, &mut robots, server.url("/private")));
+        let url: Url = format!("http://{}/private", stalled).parse().unwrap();
+        assert!(allowed(&mut core, &mut robots, url));
+        let snapshot = stats.snapshot();
+        assert_eq!((snapshot.pages(), snapshot.errors), (1, 2));
+    }
+
+    #[test]
+    fn middleware_allows_everything_without_robots() {
+        let server = TestServer::start(Vec::new());
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let mut robots = RobotsTxtMiddleware::new(client, "bot");
+        assert!(allowed(&mut core, &mut robots, server.url("/private")));
+    }
+}
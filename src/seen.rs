@@ -0,0 +1,160 @@
+use failure::Error;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::hash::Hash;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::PathBuf;
+
+/// A key which can be remembered by `SeenSet` and persisted one per line,
+/// `SeenSet` escapes line breaks in the line.
+pub trait SeenKey: Hash + Eq + Sized {
+    fn to_line(&self) -> String;
+    fn from_line(line: &str) -> Option<Self>;
+}
+
+impl SeenKey for String {
+    fn to_line(&self) -> String {
+        self.clone()
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        Some(line.to_owned())
+    }
+}
+
+fn escape(line: &str) -> String {
+    let mut escaped = String::with_capacity(line.len());
+    for c in line.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(line: &str) -> String {
+    let mut unescaped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Where a `SeenSet` keeps its keys.
+#[derive(Clone, Debug)]
+pub enum SeenStorage {
+    Memory,
+    /// Keys are loaded from the file on open and appended to it as they are seen,
+    /// so the set survives between crawls.
+    File(PathBuf),
+}
+
+pub struct SeenSet<K> {
+    seen: HashSet<K>,
+    file: Option<LineWriter<File>>,
+}
+
+#[allow(dead_code)]
+impl<K> SeenSet<K>
+where
+    K: SeenKey,
+{
+    pub fn in_memory() -> Self {
+        let seen = HashSet::new();
+        let file = None;
+        SeenSet { seen, file }
+    }
+
+    pub fn open(storage: &SeenStorage) -> Result<Self, Error> {
+        match *storage {
+            SeenStorage::Memory => Ok(Self::in_memory()),
+            SeenStorage::File(ref path) => {
+                let mut seen = HashSet::new();
+                if path.exists() {
+                    let reader = BufReader::new(File::open(path)?);
+                    for line in reader.lines() {
+                        if let Some(key) = K::from_line(&unescape(&line?)) {
+                            seen.insert(key);
+                        }
+                    }
+                }
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let file = Some(LineWriter::new(file));
+                Ok(SeenSet { seen, file })
+            }
+        }
+    }
+
+    /// Returns `true` if the key was seen before, otherwise remembers it.
+    pub fn check_and_insert(&mut self, key: K) -> Result<bool, Error> {
+        if self.seen.contains(&key) {
+            return Ok(true);
+        }
+        if let Some(ref mut file) = self.file {
+            writeln!(file, "{}", escape(&key.to_line()))?;
+        }
+        self.seen.insert(key);
+        Ok(false)
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.seen.contains(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn escape_round_trip() {
+        for line in &["plain", "two\nlines", "cr\r\n", "back\\slash\\n", "trailing\\"] {
+            let escaped = escape(line);
+            assert!(!escaped.contains('\n') && !escaped.contains('\r'));
+            assert_eq!(unescape(&escaped), *line);
+        }
+    }
+
+    #[test]
+    fn file_keys_with_line_breaks_survive_reload() {
+        let path = env::temp_dir().join(format!("seen-test-{}", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        let storage = SeenStorage::File(path.clone());
+        let keys = vec!["a\nb".to_owned(), "a".to_owned(), "b".to_owned()];
+        {
+            let mut seen = SeenSet::<String>::open(&storage).unwrap();
+            for key in &keys {
+                assert!(!seen.check_and_insert(key.clone()).unwrap());
+            }
+        }
+        let seen = SeenSet::<String>::open(&storage).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(seen.len(), 3);
+        for key in &keys {
+            assert!(seen.contains(key));
+        }
+    }
+}
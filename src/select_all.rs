@@ -69,13 +69,17 @@ impl<S: Stream> Stream for SelectAll<S> {
     type Error = S::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        match self.inner.poll().map_err(|(err, _)| err)? {
-            Async::NotReady => Ok(Async::NotReady),
-            Async::Ready(Some((Some(item), remaining))) => {
-                self.push(remaining);
-                Ok(Async::Ready(Some(item)))
+        loop {
+            match self.inner.poll().map_err(|(err, _)| err)? {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(Some((Some(item), remaining))) => {
+                    self.push(remaining);
+                    return Ok(Async::Ready(Some(item)));
+                }
+                // a finished stream is dropped, the others go on
+                Async::Ready(Some((None, _))) => {}
+                Async::Ready(None) => return Ok(Async::Ready(None)),
             }
-            Async::Ready(_) => Ok(Async::Ready(None)),
         }
     }
 }
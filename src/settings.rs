@@ -0,0 +1,142 @@
+use crawler::ParseSettings;
+use failure::Error;
+use std::env;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+use toml;
+
+/// Prefix of environment variables overriding settings, e.g. `CRAWLER_POOL_SIZE`.
+pub const SETTINGS_ENV_PREFIX: &str = "CRAWLER_";
+
+/// Crawler settings, see `CrawlerBuilder::with_settings`.
+///
+/// Every field is optional, missing ones keep the builder value.
+/// Durations are in seconds, sizes and counts of the pool, buffers and threads
+/// must be at least 1.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Settings {
+    pub pool_size: Option<usize>,
+    pub parse_mode: Option<ParseSettings>,
+    pub request_buffer: Option<usize>,
+    pub max_pending_parses: Option<usize>,
+    pub max_pending_items: Option<usize>,
+    pub max_pending_requests: Option<usize>,
+    pub user_agent: Option<String>,
+    pub proxy: Option<String>,
+    pub download_threads: Option<usize>,
+    pub max_response_size: Option<usize>,
+    pub warn_response_size: Option<usize>,
+    pub connect_timeout: Option<f64>,
+    pub download_timeout: Option<f64>,
+    pub close_on_items: Option<u64>,
+    pub close_on_pages: Option<u64>,
+    pub close_on_errors: Option<u64>,
+    pub close_timeout: Option<f64>,
+    pub shutdown_deadline: Option<f64>,
+}
+
+#[allow(dead_code)]
+impl Settings {
+    /// Loads settings from a TOML file, environment variables win over the file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        let settings: Settings = toml::from_str(&text)?;
+        settings.with_env()
+    }
+
+    /// Settings only from environment variables.
+    pub fn from_env() -> Result<Self, Error> {
+        Settings::default().with_env()
+    }
+
+    fn with_env(mut self) -> Result<Self, Error> {
+        override_from_env(&mut self.pool_size, "POOL_SIZE")?;
+        override_from_env(&mut self.parse_mode, "PARSE_MODE")?;
+        override_from_env(&mut self.request_buffer, "REQUEST_BUFFER")?;
+        override_from_env(&mut self.max_pending_parses, "MAX_PENDING_PARSES")?;
+        override_from_env(&mut self.max_pending_items, "MAX_PENDING_ITEMS")?;
+        override_from_env(&mut self.max_pending_requests, "MAX_PENDING_REQUESTS")?;
+        override_from_env(&mut self.user_agent, "USER_AGENT")?;
+        override_from_env(&mut self.proxy, "PROXY")?;
+        override_from_env(&mut self.download_threads, "DOWNLOAD_THREADS")?;
+        override_from_env(&mut self.max_response_size, "MAX_RESPONSE_SIZE")?;
+        override_from_env(&mut self.warn_response_size, "WARN_RESPONSE_SIZE")?;
+        override_from_env(&mut self.connect_timeout, "CONNECT_TIMEOUT")?;
+        override_from_env(&mut self.download_timeout, "DOWNLOAD_TIMEOUT")?;
+        override_from_env(&mut self.close_on_items, "CLOSE_ON_ITEMS")?;
+        override_from_env(&mut self.close_on_pages, "CLOSE_ON_PAGES")?;
+        override_from_env(&mut self.close_on_errors, "CLOSE_ON_ERRORS")?;
+        override_from_env(&mut self.close_timeout, "CLOSE_TIMEOUT")?;
+        override_from_env(&mut self.shutdown_deadline, "SHUTDOWN_DEADLINE")?;
+        self.validate()
+    }
+
+    fn validate(self) -> Result<Self, Error> {
+        let counts = [
+            ("pool_size", self.pool_size),
+            ("request_buffer", self.request_buffer),
+            ("max_pending_parses", self.max_pending_parses),
+            ("max_pending_items", self.max_pending_items),
+            ("max_pending_requests", self.max_pending_requests),
+            ("download_threads", self.download_threads),
+        ];
+        for &(name, count) in &counts {
+            if count == Some(0) {
+                bail!("{} must be at least 1", name);
+            }
+        }
+        Ok(self)
+    }
+}
+
+fn override_from_env<T>(value: &mut Option<T>, name: &str) -> Result<(), Error>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let name = format!("{}{}", SETTINGS_ENV_PREFIX, name);
+    if let Ok(var) = env::var(&name) {
+        let parsed = var
+            .parse()
+            .map_err(|e| format_err!("wrong value of {}: {}", name, e))?;
+        *value = Some(parsed);
+    }
+    Ok(())
+}
+
+/// Duration of a number of seconds from settings, meta or robots.txt, negative is zero.
+/// Negative and NaN values are zero.
+#[allow(clippy::neg_cmp_op_on_partial_ord)]
+pub(crate) fn seconds(seconds: f64) -> Duration {
+    if !(seconds > 0.0) {
+        return Duration::from_millis(0);
+    }
+    Duration::from_millis((seconds * 1000.0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_counts_are_rejected() {
+        for name in &["pool_size", "request_buffer", "max_pending_items"] {
+            let settings: Settings = toml::from_str(&format!("{} = 0", name)).unwrap();
+            assert!(settings.validate().is_err(), "{} = 0 was accepted", name);
+        }
+        let settings: Settings = toml::from_str("pool_size = 2\nrequest_buffer = 1").unwrap();
+        assert!(settings.validate().is_ok());
+    }
+
+    #[test]
+    fn seconds_are_clamped_to_zero() {
+        assert_eq!(seconds(1.5), Duration::from_millis(1500));
+        assert_eq!(seconds(-1.0), Duration::from_millis(0));
+        assert_eq!(seconds(f64::NAN), Duration::from_millis(0));
+    }
+}
@@ -1,16 +1,45 @@
+use downloader::{DownloadFuture, Downloaded, Downloader};
+use events::{Event, EventBus};
 use failure::Error;
 use futures::stream::{empty, Fuse, FuturesUnordered, Stream};
 use futures::task::{current, Task};
 use futures::{Async, Future, Poll};
 use request::Request;
-use reqwest::unstable::async::{Client, Response};
+use response::Response;
+use reqwest::unstable::async::Client;
 use slog::Logger;
 use spider::InternalRequestStream;
+use stats::{Stats, DROP_DOWNLOADER_MIDDLEWARE};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
 use std::convert::From;
+use std::io;
+use std::mem::replace;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::{Handle, Timeout};
+use url::Url;
 
 pub trait Sheduler: Stream<Error = Error, Item = Response> {
     fn shedule(&mut self, requests: InternalRequestStream);
+    /// Requests fetched by spiders go before the sheduled streams, which may be
+    /// waiting for the responses of these requests.
+    fn shedule_fetches(&mut self, requests: InternalRequestStream);
     fn is_done(&self) -> bool;
+    fn set_downloader(&mut self, downloader: Downloader);
+    fn set_stats(&mut self, stats: Stats);
+    fn set_events(&mut self, events: EventBus);
+    /// Downloads in progress are also limited by the limit shared with other shedulers.
+    fn set_global_limit(&mut self, global_limit: GlobalLimit);
+    /// Requests which middlewares asked to shedule again, they should go through
+    /// the same filtering as any new request.
+    fn take_rescheduled(&mut self) -> Vec<Request>;
+    /// Stops taking new requests and forgets the sheduled and rescheduled ones,
+    /// downloads in progress are finished.
+    fn close(&mut self);
+    /// Takes new requests again after `close`, called when a crawl starts,
+    /// nothing sheduled before is downloaded.
+    fn open(&mut self);
 }
 
 struct ShedulerRequestStream(Option<Fuse<InternalRequestStream>>, Option<Task>);
@@ -48,6 +77,11 @@ impl ShedulerRequestStream {
     fn is_done(&self) -> bool {
         self.as_ref().is_done()
     }
+
+    /// Forgets the requests which were not taken yet.
+    fn clear(&mut self) {
+        self.0 = Some((Box::new(empty()) as InternalRequestStream).fuse());
+    }
 }
 
 impl Stream for ShedulerRequestStream {
@@ -69,9 +103,21 @@ impl From<InternalRequestStream> for ShedulerRequestStream {
 
 pub struct GlobalLimitedSheduler<'a> {
     stream: ShedulerRequestStream,
+    fetches: ShedulerRequestStream,
     client: &'a Client,
+    downloader: Downloader,
     limit: u64,
-    executing: FuturesUnordered<Box<Future<Item = Response, Error = Error>>>,
+    executing: FuturesUnordered<DownloadFuture>,
+    /// Requests waiting for their host delay, they don't take a download slot.
+    delayed: FuturesUnordered<Box<Future<Item = Request, Error = !>>>,
+    /// Requests ready for download, they go before new requests.
+    ready: VecDeque<Request>,
+    rescheduled: Vec<Request>,
+    host_delays: Option<HostDelays>,
+    stats: Stats,
+    events: EventBus,
+    global_limit: Option<GlobalLimit>,
+    closed: bool,
     logger: Option<Logger>,
 }
 
@@ -80,12 +126,23 @@ impl<'a> GlobalLimitedSheduler<'a> {
     pub fn new(client: &'a Client, limit: u64) -> Self {
         let executing = FuturesUnordered::new();
         let stream = (Box::new(empty()) as InternalRequestStream).into();
+        let fetches = (Box::new(empty()) as InternalRequestStream).into();
         let logger = None;
         Self {
             client,
             stream,
+            fetches,
+            downloader: Downloader::default(),
             limit,
             executing,
+            delayed: FuturesUnordered::new(),
+            ready: VecDeque::new(),
+            rescheduled: Vec::new(),
+            host_delays: None,
+            stats: Stats::default(),
+            events: EventBus::default(),
+            global_limit: None,
+            closed: false,
             logger,
         }
     }
@@ -93,15 +150,73 @@ impl<'a> GlobalLimitedSheduler<'a> {
     pub fn with_logger(client: &'a Client, limit: u64, logger: Logger) -> Self {
         let executing = FuturesUnordered::new();
         let stream = (Box::new(empty()) as InternalRequestStream).into();
+        let fetches = (Box::new(empty()) as InternalRequestStream).into();
         let logger = Some(logger);
         Self {
             client,
             stream,
+            fetches,
+            downloader: Downloader::default(),
             limit,
             executing,
+            delayed: FuturesUnordered::new(),
+            ready: VecDeque::new(),
+            rescheduled: Vec::new(),
+            host_delays: None,
+            stats: Stats::default(),
+            events: EventBus::default(),
+            global_limit: None,
+            closed: false,
             logger,
         }
     }
+
+    fn streams_done(&self) -> bool {
+        self.stream.is_done() && self.fetches.is_done()
+    }
+
+    /// Requests to the same host are sheduled no more often than the host delay allows.
+    pub fn set_host_delays(&mut self, host_delays: HostDelays) {
+        self.host_delays = Some(host_delays);
+    }
+
+    fn start(&mut self, fut: DownloadFuture) {
+        let fut = match self.global_limit {
+            Some(ref global_limit) => {
+                let permit = global_limit.acquire();
+                let fut = fut.then(move |result| {
+                    drop(permit);
+                    result
+                });
+                Box::new(fut) as DownloadFuture
+            }
+            None => fut,
+        };
+        self.executing.push(fut);
+    }
+
+    /// Waits for the host delay before the request is downloaded, the delay is
+    /// reserved after the middlewares, so robots.txt `Crawl-delay` is known.
+    fn download_when_allowed(&mut self, req: Request) {
+        let wait = match self.host_delays {
+            Some(ref host_delays) => host_delays.timeout_for(req.url()),
+            None => Ok(None),
+        };
+        match wait {
+            Ok(Some(timeout)) => {
+                // a failed timer only makes the request go early
+                let fut = timeout.then(move |_| Ok(req));
+                self.delayed.push(Box::new(fut));
+            }
+            Ok(None) => self.ready.push_back(req),
+            Err(e) => {
+                if let Some(ref logger) = self.logger {
+                    warn!(logger, "failed to wait for the host delay"; "request" => %req, "error" => %e);
+                }
+                self.ready.push_back(req);
+            }
+        }
+    }
 }
 
 impl<'a> Sheduler for GlobalLimitedSheduler<'a> {
@@ -109,8 +224,48 @@ impl<'a> Sheduler for GlobalLimitedSheduler<'a> {
         ShedulerRequestStream::chain(&mut self.stream, requests);
     }
 
+    fn shedule_fetches(&mut self, requests: InternalRequestStream) {
+        ShedulerRequestStream::chain(&mut self.fetches, requests);
+    }
+
     fn is_done(&self) -> bool {
-        self.stream.is_done() && self.executing.is_empty()
+        (self.closed || self.streams_done()) && self.executing.is_empty()
+            && self.delayed.is_empty() && self.ready.is_empty()
+            && self.rescheduled.is_empty()
+    }
+
+    fn set_downloader(&mut self, downloader: Downloader) {
+        self.downloader = downloader;
+    }
+
+    fn set_stats(&mut self, stats: Stats) {
+        self.stats = stats;
+    }
+
+    fn set_events(&mut self, events: EventBus) {
+        self.events = events;
+    }
+
+    fn set_global_limit(&mut self, global_limit: GlobalLimit) {
+        self.global_limit = Some(global_limit);
+    }
+
+    fn take_rescheduled(&mut self) -> Vec<Request> {
+        replace(&mut self.rescheduled, Vec::new())
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.stream.clear();
+        self.fetches.clear();
+        self.rescheduled.clear();
+    }
+
+    fn open(&mut self) {
+        self.closed = false;
+        self.stream.clear();
+        self.fetches.clear();
+        self.rescheduled.clear();
     }
 }
 
@@ -120,26 +275,76 @@ impl<'a> Stream for GlobalLimitedSheduler<'a> {
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         loop {
+            while let Ok(Async::Ready(Some(req))) = self.delayed.poll() {
+                self.ready.push_back(req);
+            }
+
             // nothing fancy here, just copy paste from BufferUnordered
             while self.executing.len() < self.limit as usize {
-                let req = match self.stream.poll()? {
-                    Async::Ready(Some(s)) => s,
-                    Async::Ready(None) | Async::NotReady => break,
+                if let Some(ref global_limit) = self.global_limit {
+                    if !global_limit.poll_available() {
+                        break;
+                    }
+                }
+                let fut = match self.ready.pop_front() {
+                    Some(req) => self.downloader.download(self.client, req),
+                    None if self.closed => break,
+                    None => {
+                        let req = match self.fetches.poll()? {
+                            Async::Ready(Some(req)) => req,
+                            Async::Ready(None) | Async::NotReady => match self.stream.poll()? {
+                                Async::Ready(Some(req)) => req,
+                                Async::Ready(None) | Async::NotReady => break,
+                            },
+                        };
+                        self.events.emit(|| Event::RequestSheduled {
+                            url: req.url().clone(),
+                        });
+                        self.stats.request_sheduled();
+                        self.downloader.process(req)
+                    }
                 };
-                let fut = self.client.execute(req.into()).map_err(|e| e.into());
-                self.executing.push(Box::new(fut));
+                self.start(fut);
             }
 
             match self.executing.poll() {
                 Err(e) => {
+                    self.stats.request_failed();
+                    self.events.emit(|| Event::RequestFailed {
+                        error: e.to_string(),
+                    });
                     if let Some(ref logger) = self.logger {
                         error!(logger, "request failed"; "error" => %e);
                     }
                 }
                 Ok(Async::NotReady) => return Ok(Async::NotReady),
-                Ok(Async::Ready(Some(resp))) => return Ok(Async::Ready(Some(resp))),
+                Ok(Async::Ready(Some(Downloaded::Ready(req)))) => self.download_when_allowed(req),
+                Ok(Async::Ready(Some(Downloaded::Response(resp)))) => {
+                    self.stats.response_received(resp.status(), resp.body().len());
+                    self.events.emit(|| Event::ResponseReceived {
+                        url: resp.url().clone(),
+                        status: resp.status(),
+                    });
+                    return Ok(Async::Ready(Some(resp)));
+                }
+                Ok(Async::Ready(Some(Downloaded::Reschedule(req)))) => {
+                    if let Some(ref logger) = self.logger {
+                        debug!(logger, "request rescheduled"; "request" => %req);
+                    }
+                    self.stats.request_rescheduled();
+                    self.rescheduled.push(req);
+                }
+                Ok(Async::Ready(Some(Downloaded::Dropped))) => {
+                    self.stats.dropped(DROP_DOWNLOADER_MIDDLEWARE);
+                    self.events.emit(|| Event::RequestDropped {
+                        url: None,
+                        reason: DROP_DOWNLOADER_MIDDLEWARE,
+                    });
+                }
                 Ok(Async::Ready(None)) => {
-                    if self.stream.as_ref().is_done() {
+                    // delayed requests notify the task when they are ready
+                    let waiting = !self.delayed.is_empty() || !self.ready.is_empty();
+                    if !waiting && (self.closed || self.streams_done()) {
                         return Ok(Async::Ready(None));
                     } else {
                         return Ok(Async::NotReady);
@@ -153,3 +358,175 @@ impl<'a> Stream for GlobalLimitedSheduler<'a> {
 fn filter_request(req: &Request) -> bool {
     req.url().has_host()
 }
+
+struct GlobalLimitInner {
+    limit: usize,
+    in_flight: usize,
+    waiting: Vec<Task>,
+}
+
+/// Limit of downloads in progress shared by several shedulers,
+/// e.g. of spiders run by `CrawlerProcess`.
+#[derive(Clone)]
+pub struct GlobalLimit {
+    inner: Rc<RefCell<GlobalLimitInner>>,
+}
+
+#[allow(dead_code)]
+impl GlobalLimit {
+    pub fn new(limit: usize) -> Self {
+        let inner = GlobalLimitInner {
+            limit,
+            in_flight: 0,
+            waiting: Vec::new(),
+        };
+        let inner = Rc::new(RefCell::new(inner));
+        GlobalLimit { inner }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.inner.borrow().in_flight
+    }
+
+    /// Returns `true` if a download may start, otherwise the current task
+    /// is notified when a download finishes.
+    fn poll_available(&self) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        if inner.in_flight < inner.limit {
+            true
+        } else {
+            inner.waiting.push(current());
+            false
+        }
+    }
+
+    fn acquire(&self) -> GlobalPermit {
+        self.inner.borrow_mut().in_flight += 1;
+        GlobalPermit(self.clone())
+    }
+}
+
+/// Slot of a download in progress, released when the download future is dropped.
+struct GlobalPermit(GlobalLimit);
+
+impl Drop for GlobalPermit {
+    fn drop(&mut self) {
+        let mut inner = self.0.inner.borrow_mut();
+        inner.in_flight -= 1;
+        for task in inner.waiting.drain(..) {
+            task.notify();
+        }
+    }
+}
+
+struct HostDelaysInner {
+    delays: HashMap<String, Duration>,
+    next: HashMap<String, Instant>,
+}
+
+/// Minimal intervals between requests to the same host, e.g. robots.txt `Crawl-delay`.
+#[derive(Clone)]
+pub struct HostDelays {
+    handle: Handle,
+    inner: Rc<RefCell<HostDelaysInner>>,
+}
+
+#[allow(dead_code)]
+impl HostDelays {
+    pub fn new(handle: Handle) -> Self {
+        let inner = HostDelaysInner {
+            delays: HashMap::new(),
+            next: HashMap::new(),
+        };
+        let inner = Rc::new(RefCell::new(inner));
+        HostDelays { handle, inner }
+    }
+
+    pub fn set_delay(&self, host: &str, delay: Duration) {
+        let mut inner = self.inner.borrow_mut();
+        inner.delays.insert(host.to_owned(), delay);
+    }
+
+    pub fn delay(&self, host: &str) -> Option<Duration> {
+        self.inner.borrow().delays.get(host).cloned()
+    }
+
+    /// Reserves the next free slot for the host, returns a timeout to wait for
+    /// if the slot is in the future.
+    fn timeout_for(&self, url: &Url) -> io::Result<Option<Timeout>> {
+        let host = match url.host_str() {
+            Some(host) => host,
+            None => return Ok(None),
+        };
+        let mut inner = self.inner.borrow_mut();
+        let delay = match inner.delays.get(host) {
+            Some(&delay) => delay,
+            None => return Ok(None),
+        };
+        let now = Instant::now();
+        let slot = match inner.next.get(host) {
+            Some(&next) if next > now => next,
+            _ => now,
+        };
+        inner.next.insert(host.to_owned(), slot + delay);
+        if slot > now {
+            Timeout::new(slot - now, &self.handle).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream::iter_ok;
+    use reqwest::Method;
+    use test_server::{response, TestServer};
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn delayed_requests_dont_block_other_hosts() {
+        let ok = || response("200 OK", &[], "");
+        let a = TestServer::start_on("127.0.0.1", vec![("/a1", ok()), ("/a2", ok())]);
+        let b = TestServer::start_on("127.0.0.2", vec![("/b1", ok())]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let host_delays = HostDelays::new(core.handle());
+        host_delays.set_delay("127.0.0.1", Duration::from_millis(300));
+        let mut sheduler = GlobalLimitedSheduler::new(&client, 1);
+        sheduler.set_host_delays(host_delays);
+        let requests = vec![a.url("/a1"), a.url("/a2"), b.url("/b1")]
+            .into_iter()
+            .map(|url| Request::new(Method::Get, url));
+        sheduler.shedule(Box::new(iter_ok(requests)));
+
+        let started = Instant::now();
+        let paths = sheduler.map(|resp| resp.url().path().to_owned()).collect();
+        let paths: Vec<String> = core.run(paths).unwrap();
+        assert_eq!(paths, vec!["/a1", "/b1", "/a2"]);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[test]
+    fn close_forgets_sheduled_requests() {
+        let ok = || response("200 OK", &[], "");
+        let server = TestServer::start(vec![("/a", ok()), ("/b", ok()), ("/c", ok())]);
+        let mut core = Core::new().unwrap();
+        let client = Client::new(&core.handle());
+        let mut sheduler = GlobalLimitedSheduler::new(&client, 1);
+        let get = |path| Request::new(Method::Get, server.url(path));
+        sheduler.shedule(Box::new(iter_ok(vec![get("/a"), get("/b")])));
+
+        let (first, mut sheduler) = core.run(sheduler.into_future()).map_err(|(e, _)| e).unwrap();
+        assert_eq!(first.unwrap().url().path(), "/a");
+        sheduler.close();
+        assert!(sheduler.is_done());
+        sheduler.open();
+        sheduler.shedule(Box::new(iter_ok(vec![get("/c")])));
+        let paths = sheduler.map(|resp| resp.url().path().to_owned()).collect();
+        let paths: Vec<String> = core.run(paths).unwrap();
+        assert_eq!(paths, vec!["/c"]);
+        assert_eq!(server.requests(), vec!["/a", "/c"]);
+    }
+}
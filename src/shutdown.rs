@@ -0,0 +1,98 @@
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{Future, Stream};
+use slog::Logger;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio_core::reactor::Handle;
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+pub(crate) enum Shutdown {
+    Graceful,
+    Forced,
+}
+
+/// Stops a running crawl, can be sent to other threads.
+///
+/// A graceful shutdown stops sheduling new requests and lets downloads and parses
+/// in progress finish, a forced one ends the crawl immediately.
+///
+/// Nothing is flushed on shutdown, there are no item pipelines or exporters, items
+/// are written out by the consumer of the crawl stream. After a graceful shutdown
+/// the stream still yields the items of finished parses before it ends, after a
+/// forced one items of parses in progress are lost. `Spider::closed` is called either way.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: UnboundedSender<Shutdown>,
+    requested: Arc<AtomicBool>,
+}
+
+#[allow(dead_code)]
+impl ShutdownHandle {
+    pub(crate) fn new() -> (Self, UnboundedReceiver<Shutdown>) {
+        let (sender, receiver) = unbounded();
+        let requested = Arc::new(AtomicBool::new(false));
+        (ShutdownHandle { sender, requested }, receiver)
+    }
+
+    /// Shuts the crawl down gracefully, the second call forces it.
+    pub fn shutdown(&self) {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            self.force();
+        } else {
+            // the crawl is gone if sending fails, nothing to shut down
+            let _ = self.sender.unbounded_send(Shutdown::Graceful);
+        }
+    }
+
+    pub fn force(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        let _ = self.sender.unbounded_send(Shutdown::Forced);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+/// Shuts the crawl down on SIGINT and SIGTERM, the second signal forces it.
+pub fn shutdown_on_signals(handle: &Handle, shutdown: ShutdownHandle, logger: Option<Logger>) {
+    let sigint = Signal::new(SIGINT, handle).flatten_stream();
+    let sigterm = Signal::new(SIGTERM, handle).flatten_stream();
+    let signal_logger = logger.clone();
+    let signals = sigint.select(sigterm).for_each(move |signal| {
+        if let Some(ref logger) = signal_logger {
+            if shutdown.is_requested() {
+                info!(logger, "forcing shutdown"; "signal" => signal);
+            } else {
+                info!(logger, "shutting down, send the signal again to force it"; "signal" => signal);
+            }
+        }
+        shutdown.shutdown();
+        Ok(())
+    });
+    handle.spawn(signals.map_err(move |e| {
+        if let Some(ref logger) = logger {
+            error!(logger, "failed to listen for signals"; "error" => %e);
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_shutdown_forces() {
+        let (handle, receiver) = ShutdownHandle::new();
+        assert!(!handle.is_requested());
+        handle.shutdown();
+        assert!(handle.is_requested());
+        handle.clone().shutdown();
+        drop(handle);
+        let requests: Vec<_> = receiver.collect().wait().unwrap();
+        match requests[..] {
+            [Shutdown::Graceful, Shutdown::Forced] => {}
+            _ => panic!("expected a graceful and then a forced shutdown"),
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use failure::Error;
+use futures::future::{err, ok};
+use futures::sync::oneshot;
+use futures::Future;
+use hyper;
+use hyper::client::Service;
+use hyper::header::ContentLength;
+use hyper::Uri;
+use hyper_tls::HttpsConnector;
+use native_tls::TlsConnector;
+use request::Request;
+use std::cell::Cell;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::rc::Rc;
+use std::thread;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Handle;
+use tokio_io::io::{read_exact, write_all};
+use url::Url;
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASSWORD: u8 = 2;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+/// `hyper` client which connects through a SOCKS5 proxy, `reqwest` only supports http proxies.
+///
+/// It bypasses the `reqwest` settings of `ClientConfig`: responses are not decoded
+/// from gzip and redirects are never followed, use `RedirectMiddleware` for them.
+pub(crate) type SocksClient = hyper::Client<HttpsConnector<SocksConnector>>;
+
+/// Builds a client for a `socks5://` or `socks5h://` proxy url, host names of
+/// requests are resolved by the proxy in both cases. Credentials of the url are
+/// sent with username/password authentication.
+///
+/// The host name of the proxy itself is resolved on a thread when the first
+/// connection is opened, so building the client doesn't block the reactor.
+pub(crate) fn socks_client(proxy: &Url, handle: &Handle) -> Result<SocksClient, Error> {
+    let host = proxy
+        .host_str()
+        .ok_or_else(|| format_err!("SOCKS proxy {} has no host", proxy))?
+        .trim_matches(|c| c == '[' || c == ']')
+        .to_owned();
+    let port = proxy.port().unwrap_or(1080);
+    let addr = host.parse().ok().map(|ip| SocketAddr::new(ip, port));
+    let auth = match (proxy.username(), proxy.password()) {
+        ("", None) => None,
+        (username, password) => {
+            let password = password.unwrap_or("");
+            if username.len() > 255 || password.len() > 255 {
+                bail!("SOCKS proxy credentials are longer than 255 bytes");
+            }
+            Some((username.to_owned(), password.to_owned()))
+        }
+    };
+    let connector = SocksConnector {
+        host,
+        port,
+        addr: Rc::new(Cell::new(addr)),
+        auth,
+        handle: handle.clone(),
+    };
+    let tls = TlsConnector::builder()?.build()?;
+    let client = hyper::Client::configure()
+        .connector(HttpsConnector::from((connector, tls)))
+        .build(handle);
+    Ok(client)
+}
+
+/// Converts the request for the `hyper` client.
+pub(crate) fn socks_request(request: Request) -> Result<hyper::Request, Error> {
+    let uri: Uri = request.url().as_str().parse()?;
+    let mut socks_request = hyper::Request::new(request.method().clone(), uri);
+    *socks_request.headers_mut() = request.headers().clone();
+    if let Some(body) = request.body() {
+        if !socks_request.headers().has::<ContentLength>() {
+            socks_request
+                .headers_mut()
+                .set(ContentLength(body.as_ref().len() as u64));
+        }
+        socks_request.set_body(body.as_ref().to_vec());
+    }
+    Ok(socks_request)
+}
+
+/// Opens connections to the target through the proxy.
+pub(crate) struct SocksConnector {
+    host: String,
+    port: u16,
+    /// Address of the proxy once it is resolved, a failed resolution is tried again.
+    addr: Rc<Cell<Option<SocketAddr>>>,
+    auth: Option<(String, String)>,
+    handle: Handle,
+}
+
+impl SocksConnector {
+    fn proxy_addr(&self) -> Box<Future<Item = SocketAddr, Error = io::Error>> {
+        if let Some(addr) = self.addr.get() {
+            return Box::new(ok(addr));
+        }
+        let (tx, rx) = oneshot::channel();
+        let proxy = (self.host.clone(), self.port);
+        thread::spawn(move || {
+            let addr = proxy.to_socket_addrs().and_then(|mut addrs| {
+                addrs
+                    .next()
+                    .ok_or_else(|| socks_error(&format!("{} has no address", proxy.0)))
+            });
+            let _ = tx.send(addr);
+        });
+        let cache = self.addr.clone();
+        let fut = rx
+            .map_err(|_| socks_error("proxy address was not resolved"))
+            .and_then(|addr| addr)
+            .map(move |addr| {
+                cache.set(Some(addr));
+                addr
+            });
+        Box::new(fut)
+    }
+}
+
+impl Service for SocksConnector {
+    type Request = Uri;
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Box<Future<Item = TcpStream, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        let host = match uri.host() {
+            Some(host) => host.trim_matches(|c| c == '[' || c == ']').to_owned(),
+            None => return Box::new(err(socks_error("url has no host"))),
+        };
+        let port = uri.port()
+            .unwrap_or(if uri.scheme() == Some("https") { 443 } else { 80 });
+        let auth = self.auth.clone();
+        let handle = self.handle.clone();
+        let fut = self.proxy_addr()
+            .and_then(move |addr| TcpStream::connect(&addr, &handle))
+            .and_then(move |stream| authenticate(stream, auth))
+            .and_then(move |stream| connect(stream, &host, port));
+        Box::new(fut)
+    }
+}
+
+fn socks_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("SOCKS proxy: {}", message))
+}
+
+fn authenticate(
+    stream: TcpStream,
+    auth: Option<(String, String)>,
+) -> Box<Future<Item = TcpStream, Error = io::Error>> {
+    let methods = match auth {
+        Some(_) => vec![VERSION, 2, NO_AUTH, USER_PASSWORD],
+        None => vec![VERSION, 1, NO_AUTH],
+    };
+    let fut = write_all(stream, methods)
+        .and_then(|(stream, _)| read_exact(stream, [0; 2]))
+        .and_then(move |(stream, reply)| -> Box<Future<Item = TcpStream, Error = io::Error>> {
+            match (reply[1], auth) {
+                (NO_AUTH, _) => Box::new(ok(stream)),
+                (USER_PASSWORD, Some((username, password))) => {
+                    let mut message = vec![1, username.len() as u8];
+                    message.extend_from_slice(username.as_bytes());
+                    message.push(password.len() as u8);
+                    message.extend_from_slice(password.as_bytes());
+                    let fut = write_all(stream, message)
+                        .and_then(|(stream, _)| read_exact(stream, [0; 2]))
+                        .and_then(|(stream, reply)| match reply[1] {
+                            0 => Ok(stream),
+                            _ => Err(socks_error("authentication failed")),
+                        });
+                    Box::new(fut)
+                }
+                _ => Box::new(err(socks_error("no acceptable authentication method"))),
+            }
+        });
+    Box::new(fut)
+}
+
+fn connect(
+    stream: TcpStream,
+    host: &str,
+    port: u16,
+) -> Box<Future<Item = TcpStream, Error = io::Error>> {
+    let mut message = vec![VERSION, CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            message.push(IPV4);
+            message.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            message.push(IPV6);
+            message.extend_from_slice(&ip.octets());
+        }
+        Err(_) if host.len() > 255 => return Box::new(err(socks_error("host name is too long"))),
+        Err(_) => {
+            message.push(DOMAIN);
+            message.push(host.len() as u8);
+            message.extend_from_slice(host.as_bytes());
+        }
+    }
+    message.push((port >> 8) as u8);
+    message.push(port as u8);
+    // the reply ends with the bound address, its fifth byte is the length of a domain
+    let fut = write_all(stream, message)
+        .and_then(|(stream, _)| read_exact(stream, [0; 5]))
+        .and_then(|(stream, reply)| {
+            if reply[1] != 0 {
+                return Err(socks_error(&format!("connect failed with reply {}", reply[1])));
+            }
+            let remaining = match reply[3] {
+                IPV4 => 4 - 1 + 2,
+                IPV6 => 16 - 1 + 2,
+                DOMAIN => reply[4] as usize + 2,
+                _ => return Err(socks_error("invalid bound address")),
+            };
+            Ok((stream, remaining))
+        })
+        .and_then(|(stream, remaining)| read_exact(stream, vec![0; remaining]))
+        .map(|(stream, _)| stream);
+    Box::new(fut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn proxy_host_is_resolved_once_off_the_reactor() {
+        let mut core = Core::new().unwrap();
+        let connector = SocksConnector {
+            host: "localhost".to_owned(),
+            port: 1080,
+            addr: Rc::new(Cell::new(None)),
+            auth: None,
+            handle: core.handle(),
+        };
+        let addr = core.run(connector.proxy_addr()).unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_eq!(addr.port(), 1080);
+        assert_eq!(connector.addr.get(), Some(addr));
+    }
+}
@@ -1,8 +1,13 @@
+use close::CloseReason;
+use context::CrawlContext;
 use failure::Error;
+use futures::future::ok;
 use futures::stream::Stream;
 use futures::Future;
 use request::Request;
-use reqwest::unstable::async::Response;
+use reqwest::header::Headers;
+use response::Response;
+use stats::StatsSnapshot;
 use std::fmt::Display;
 
 pub enum Parse<T: Send> {
@@ -23,9 +28,44 @@ where
 
     fn name(&self) -> &'static str;
 
-    fn start(&mut self) -> Box<Future<Item = RequestStream, Error = Error>>;
+    /// Requests to hosts outside of these domains and their subdomains are not sheduled.
+    /// Everything is allowed if the list is empty.
+    fn allowed_domains(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// Overrides the crawler `User-Agent` for requests of this spider.
+    fn user_agent(&self) -> Option<&str> {
+        None
+    }
+
+    /// Headers set on requests of this spider which don't have them,
+    /// they win over the crawler default headers.
+    fn default_headers(&self) -> Headers {
+        Headers::new()
+    }
+
+    /// Called when the crawl starts, `start` is called after the future resolves,
+    /// e.g. to log in. The crawl fails if the future fails.
+    fn opened(&mut self) -> Box<Future<Item = (), Error = Error>> {
+        Box::new(ok(()))
+    }
+
+    /// Called when no requests are sheduled and no responses are being parsed,
+    /// returned requests are sheduled and the crawl goes on, e.g. with the next
+    /// batch of a work list. The crawl ends when it returns `None`.
+    fn idle(&mut self) -> Option<RequestStream> {
+        None
+    }
+
+    /// Called when the crawl ends, e.g. to save state or log a summary.
+    fn closed(&mut self, _reason: &CloseReason, _stats: &StatsSnapshot) {}
+
+    /// The context fetches auxiliary pages through the crawl, see `CrawlContext::fetch`.
+    fn start(&mut self, context: &CrawlContext) -> Box<Future<Item = RequestStream, Error = Error>>;
     fn parse(
         &mut self,
         response: Response,
+        context: &CrawlContext,
     ) -> Box<Future<Item = ParseStream<Self::Item>, Error = Error> + Send>;
 }
@@ -0,0 +1,240 @@
+use failure::Error;
+use futures::Stream;
+use request::Request;
+use reqwest::header::Referer;
+use response::Response;
+use spider::Parse;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Hooks around `Spider::parse`.
+///
+/// `process_input` is called with each response before it is parsed,
+/// `process_request` and `process_item` with everything parse produced from it.
+/// Hooks are called in the order the middlewares were added to `Crawl`.
+pub trait SpiderMiddleware<T> {
+    fn name(&self) -> &'static str;
+
+    /// Returning an error skips parsing of the response.
+    fn process_input(&mut self, _response: &Response) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Returning `None` drops the request.
+    fn process_request(&mut self, _response: &Response, request: Request) -> Option<Request> {
+        Some(request)
+    }
+
+    /// Returning `None` drops the item.
+    fn process_item(&mut self, _response: &Response, item: T) -> Option<T> {
+        Some(item)
+    }
+}
+
+pub(crate) struct SpiderMiddlewares<T> {
+    middlewares: Rc<RefCell<Vec<Box<SpiderMiddleware<T>>>>>,
+}
+
+impl<T> SpiderMiddlewares<T>
+where
+    T: Send + 'static,
+{
+    pub fn new() -> Self {
+        let middlewares = Rc::new(RefCell::new(Vec::new()));
+        SpiderMiddlewares { middlewares }
+    }
+
+    pub fn push(&mut self, middleware: Box<SpiderMiddleware<T>>) {
+        self.middlewares.borrow_mut().push(middleware);
+    }
+
+    pub fn process_input(&self, response: &Response) -> Result<(), Error> {
+        let mut middlewares = self.middlewares.borrow_mut();
+        for middleware in middlewares.iter_mut() {
+            middleware.process_input(response)?;
+        }
+        Ok(())
+    }
+
+    pub fn process_output<S>(
+        &self,
+        response: Rc<Response>,
+        output: S,
+    ) -> impl Stream<Item = Parse<T>, Error = !>
+    where
+        S: Stream<Item = Parse<T>, Error = !>,
+    {
+        let middlewares = self.middlewares.clone();
+        output.filter_map(move |parsed| {
+            let mut middlewares = middlewares.borrow_mut();
+            let mut parsed = Some(parsed);
+            for middleware in middlewares.iter_mut() {
+                parsed = match parsed {
+                    Some(Parse::Request(req)) => middleware
+                        .process_request(&response, req)
+                        .map(Parse::Request),
+                    Some(Parse::Item(item)) => {
+                        middleware.process_item(&response, item).map(Parse::Item)
+                    }
+                    None => break,
+                };
+            }
+            parsed
+        })
+    }
+}
+
+/// Tracks request depth and drops requests deeper than `max_depth`.
+pub struct DepthMiddleware {
+    max_depth: Option<u32>,
+}
+
+#[allow(dead_code)]
+impl DepthMiddleware {
+    pub fn new() -> Self {
+        let max_depth = None;
+        DepthMiddleware { max_depth }
+    }
+
+    pub fn with_max_depth(max_depth: u32) -> Self {
+        let max_depth = Some(max_depth);
+        DepthMiddleware { max_depth }
+    }
+}
+
+impl<T> SpiderMiddleware<T> for DepthMiddleware {
+    fn name(&self) -> &'static str {
+        "DepthMiddleware"
+    }
+
+    fn process_request(&mut self, response: &Response, mut request: Request) -> Option<Request> {
+        let depth = response.request().depth() + 1;
+        *request.depth_mut() = depth;
+        match self.max_depth {
+            Some(max_depth) if depth > max_depth => None,
+            _ => Some(request),
+        }
+    }
+}
+
+/// Sets the `Referer` header of new requests to the url of the response they came from.
+pub struct RefererMiddleware;
+
+impl<T> SpiderMiddleware<T> for RefererMiddleware {
+    fn name(&self) -> &'static str {
+        "RefererMiddleware"
+    }
+
+    fn process_request(&mut self, response: &Response, mut request: Request) -> Option<Request> {
+        if !request.headers().has::<Referer>() {
+            request
+                .headers_mut()
+                .set(Referer::new(response.url().to_string()));
+        }
+        Some(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures::stream::iter_ok;
+    use futures::Future;
+    use reqwest::header::Headers;
+    use reqwest::{Method, StatusCode};
+
+    fn response_at_depth(depth: u32) -> Response {
+        let url = "http://example.com/".parse().unwrap();
+        let mut request = Request::new(Method::Get, url);
+        *request.depth_mut() = depth;
+        let url = request.url().clone();
+        Response::new(url, StatusCode::Ok, Headers::new(), Bytes::new(), request)
+    }
+
+    fn request(path: &str) -> Request {
+        let url = "http://example.com".parse::<::url::Url>().unwrap();
+        Request::new(Method::Get, url.join(path).unwrap())
+    }
+
+    /// Appends its name to every item and drops items ending with `drop`.
+    struct Append(&'static str);
+
+    impl SpiderMiddleware<String> for Append {
+        fn name(&self) -> &'static str {
+            "Append"
+        }
+
+        fn process_item(&mut self, _response: &Response, item: String) -> Option<String> {
+            if item.ends_with("drop") {
+                return None;
+            }
+            Some(item + self.0)
+        }
+    }
+
+    fn process(
+        middlewares: Vec<Box<SpiderMiddleware<String>>>,
+        output: Vec<Parse<String>>,
+    ) -> Vec<Parse<String>> {
+        let mut chain = SpiderMiddlewares::new();
+        for middleware in middlewares {
+            chain.push(middleware);
+        }
+        let response = Rc::new(response_at_depth(0));
+        chain
+            .process_output(response, iter_ok::<_, !>(output))
+            .collect()
+            .wait()
+            .unwrap()
+    }
+
+    fn items(parsed: Vec<Parse<String>>) -> Vec<String> {
+        parsed
+            .into_iter()
+            .filter_map(|parse| match parse {
+                Parse::Item(item) => Some(item),
+                Parse::Request(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn output_goes_through_middlewares_in_order() {
+        let middlewares: Vec<Box<SpiderMiddleware<String>>> =
+            vec![Box::new(Append("a")), Box::new(Append("b"))];
+        let output = vec![Parse::Item("1".to_owned()), Parse::Item("2".to_owned())];
+        assert_eq!(items(process(middlewares, output)), vec!["1ab", "2ab"]);
+
+        // a dropped item isn't passed to the later middlewares
+        let middlewares: Vec<Box<SpiderMiddleware<String>>> =
+            vec![Box::new(Append("drop")), Box::new(Append("b"))];
+        let output = vec![Parse::Item("1".to_owned())];
+        assert!(process(middlewares, output).is_empty());
+    }
+
+    #[test]
+    fn depth_middleware_drops_too_deep_requests() {
+        let mut middleware = DepthMiddleware::with_max_depth(2);
+        let processed = SpiderMiddleware::<String>::process_request(
+            &mut middleware,
+            &response_at_depth(1),
+            request("/next"),
+        );
+        assert_eq!(processed.map(|request| request.depth()), Some(2));
+        let processed = SpiderMiddleware::<String>::process_request(
+            &mut middleware,
+            &response_at_depth(2),
+            request("/next"),
+        );
+        assert!(processed.is_none());
+
+        let mut middleware = DepthMiddleware::new();
+        let processed = SpiderMiddleware::<String>::process_request(
+            &mut middleware,
+            &response_at_depth(100),
+            request("/next"),
+        );
+        assert_eq!(processed.map(|request| request.depth()), Some(101));
+    }
+}
@@ -0,0 +1,173 @@
+use failure::Error;
+use futures::stream::iter_result;
+use futures::sync::mpsc::channel;
+use futures::{Future, Sink, Stream};
+use request::{Request, RequestSpec};
+use reqwest::Method;
+use serde_json;
+use spider::RequestStream;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::thread;
+use url::Url;
+
+/// Lines read from stdin ahead of the crawl.
+const STDIN_BUFFER: usize = 64;
+
+/// Format of start request lines, blank lines are skipped in both.
+#[derive(Clone, Copy, Debug)]
+pub enum StartFormat {
+    /// One url per line, lines starting with `#` are comments.
+    Urls,
+    /// One `RequestSpec` per line, only `url` is required, e.g.
+    /// `{"method": "POST", "url": "http://example.com", "headers": [["Accept", "*/*"]], "body": "aGk="}`.
+    Jsonl,
+}
+
+impl StartFormat {
+    /// `Jsonl` for `.jsonl` files, `Urls` for any other file.
+    pub fn for_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension() {
+            Some(extension) if extension == "jsonl" => StartFormat::Jsonl,
+            _ => StartFormat::Urls,
+        }
+    }
+}
+
+fn parse_line(line: &str, format: StartFormat) -> Option<Result<Request, Error>> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+    let request = match format {
+        StartFormat::Urls if line.starts_with('#') => return None,
+        StartFormat::Urls => line.parse::<Url>()
+            .map(|url| Request::new(Method::Get, url))
+            .map_err(Error::from),
+        StartFormat::Jsonl => serde_json::from_str::<RequestSpec>(line)
+            .map_err(Error::from)
+            .and_then(RequestSpec::into_request),
+    };
+    Some(request)
+}
+
+/// Turns lines into start requests, a line which can't be parsed fails only
+/// its request, a read error ends the stream.
+fn requests<S>(lines: S, format: StartFormat) -> RequestStream
+where
+    S: Stream<Item = String, Error = Error> + 'static,
+{
+    let mut number = 0;
+    let requests = lines.filter_map(move |line| {
+        number += 1;
+        parse_line(&line, format)
+            .map(|request| request.map_err(|e| format_err!("line {}: {}", number, e)))
+    });
+    Box::new(requests)
+}
+
+/// Start requests read lazily from the reader, one line at a time.
+pub fn from_reader<R>(reader: R, format: StartFormat) -> RequestStream
+where
+    R: BufRead + 'static,
+{
+    let lines = iter_result(reader.lines()).map_err(Error::from);
+    requests(lines, format)
+}
+
+/// Start requests read lazily from the file, e.g. from `Spider::start`.
+pub fn from_file<P: AsRef<Path>>(path: P, format: StartFormat) -> Result<RequestStream, Error> {
+    let file = File::open(path)?;
+    Ok(from_reader(BufReader::new(file), format))
+}
+
+/// Start requests read from stdin.
+///
+/// Stdin is read on its own thread so waiting for input doesn't block the crawl,
+/// only a few lines are read ahead of it.
+pub fn from_stdin(format: StartFormat) -> RequestStream {
+    let (sender, receiver) = channel(STDIN_BUFFER);
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut sender = sender;
+        for line in stdin.lock().lines() {
+            let failed = line.is_err();
+            sender = match sender.send(line).wait() {
+                Ok(sender) => sender,
+                // the crawl dropped the stream
+                Err(_) => return,
+            };
+            if failed {
+                return;
+            }
+        }
+    });
+    let lines = receiver
+        .map_err(|()| format_err!("stdin channel failed"))
+        .and_then(|line| line.map_err(Error::from));
+    requests(lines, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Requests of the lines, failed lines as their error messages.
+    fn parse(text: &str, format: StartFormat) -> Vec<Result<Request, String>> {
+        let requests = from_reader(Cursor::new(text.to_owned()), format);
+        requests
+            .map(|request| request.map_err(|e| e.to_string()))
+            .collect()
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn url_lines_skip_comments_and_blank_lines() {
+        let text = "# seeds\nhttp://example.com/a\n\n   \n  http://example.com/b  \n";
+        let urls: Vec<_> = parse(text, StartFormat::Urls)
+            .into_iter()
+            .map(|request| request.unwrap().url().to_string())
+            .collect();
+        assert_eq!(urls, vec!["http://example.com/a", "http://example.com/b"]);
+    }
+
+    #[test]
+    fn jsonl_lines_are_request_specs() {
+        let text = concat!(
+            r#"{"url": "http://example.com/a"}"#,
+            "\n\n",
+            r#"{"method": "POST", "url": "http://example.com/b", "headers": [["Accept", "*/*"]], "body": "aGk=", "meta": {"page": "2"}, "dont_filter": true}"#,
+        );
+        let requests: Vec<_> = parse(text, StartFormat::Jsonl)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(*requests[0].method(), Method::Get);
+        let request = &requests[1];
+        assert_eq!(*request.method(), Method::Post);
+        assert_eq!(request.url().as_str(), "http://example.com/b");
+        assert_eq!(request.headers().get_raw("Accept").unwrap().one(), Some(&b"*/*"[..]));
+        assert_eq!(request.body().unwrap().as_ref(), b"hi");
+        assert_eq!(request.meta().get("page").map(String::as_str), Some("2"));
+        assert!(request.dont_filter());
+    }
+
+    #[test]
+    fn bad_lines_fail_only_their_request() {
+        let text = "http://example.com/a\nnot an url\nhttp://example.com/b";
+        let requests = parse(text, StartFormat::Urls);
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].is_ok() && requests[2].is_ok());
+        assert!(requests[1].as_ref().err().unwrap().starts_with("line 2: "));
+
+        let text = "{\"url\": \"http://example.com\"}\n{\"method\": \"GET\"}\n{\"url\": \"http://example.com\", \"body\": \"!\"}";
+        let requests = parse(text, StartFormat::Jsonl);
+        assert!(requests[0].is_ok());
+        assert!(requests[1].as_ref().err().unwrap().starts_with("line 2: "));
+        assert!(requests[2].as_ref().err().unwrap().starts_with("line 3: "));
+    }
+}
@@ -0,0 +1,218 @@
+use reqwest::StatusCode;
+use slog::Logger;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Reasons for dropped requests, responses and items.
+pub const DROP_DUPLICATE_REQUEST: &str = "duplicate_request";
+pub const DROP_OFFSITE: &str = "offsite";
+pub const DROP_DOWNLOADER_MIDDLEWARE: &str = "downloader_middleware";
+pub const DROP_SPIDER_MIDDLEWARE: &str = "spider_middleware";
+pub const DROP_DUPLICATE_ITEM: &str = "duplicate_item";
+
+struct StatsInner {
+    started: Instant,
+    finished: Option<Instant>,
+    requests_sheduled: u64,
+    requests_rescheduled: u64,
+    responses: BTreeMap<u16, u64>,
+    bytes_downloaded: u64,
+    errors: u64,
+    items: u64,
+    dropped: BTreeMap<&'static str, u64>,
+}
+
+impl StatsInner {
+    fn new() -> Self {
+        StatsInner {
+            started: Instant::now(),
+            finished: None,
+            requests_sheduled: 0,
+            requests_rescheduled: 0,
+            responses: BTreeMap::new(),
+            bytes_downloaded: 0,
+            errors: 0,
+            items: 0,
+            dropped: BTreeMap::new(),
+        }
+    }
+}
+
+/// Counters of a crawl, the handle is cheap to clone and can be read while
+/// the crawl is running, see `Stats::snapshot`.
+#[derive(Clone)]
+pub struct Stats {
+    inner: Arc<Mutex<StatsInner>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+#[allow(dead_code)]
+impl Stats {
+    pub fn new() -> Self {
+        let inner = Arc::new(Mutex::new(StatsInner::new()));
+        Stats { inner }
+    }
+
+    fn lock(&self) -> MutexGuard<StatsInner> {
+        self.inner.lock().expect("stats mutex poisoned")
+    }
+
+    /// Resets every counter and the elapsed time.
+    pub(crate) fn start(&self) {
+        *self.lock() = StatsInner::new();
+    }
+
+    pub(crate) fn finish(&self) {
+        self.lock().finished = Some(Instant::now());
+    }
+
+    pub(crate) fn request_sheduled(&self) {
+        self.lock().requests_sheduled += 1;
+    }
+
+    pub(crate) fn request_rescheduled(&self) {
+        self.lock().requests_rescheduled += 1;
+    }
+
+    pub(crate) fn response_received(&self, status: StatusCode, bytes: usize) {
+        let mut inner = self.lock();
+        *inner.responses.entry(status.as_u16()).or_insert(0) += 1;
+        inner.bytes_downloaded += bytes as u64;
+    }
+
+    pub(crate) fn request_failed(&self) {
+        self.lock().errors += 1;
+    }
+
+    pub(crate) fn item_scraped(&self) {
+        self.lock().items += 1;
+    }
+
+    pub(crate) fn dropped(&self, reason: &'static str) {
+        *self.lock().dropped.entry(reason).or_insert(0) += 1;
+    }
+
+    /// Copy of the current counters.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let inner = self.lock();
+        let elapsed = match inner.finished {
+            Some(finished) => finished - inner.started,
+            None => inner.started.elapsed(),
+        };
+        StatsSnapshot {
+            requests_sheduled: inner.requests_sheduled,
+            requests_rescheduled: inner.requests_rescheduled,
+            responses: inner.responses.clone(),
+            bytes_downloaded: inner.bytes_downloaded,
+            errors: inner.errors,
+            items: inner.items,
+            dropped: inner.dropped.clone(),
+            elapsed,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StatsSnapshot {
+    /// Requests passed to the downloader.
+    pub requests_sheduled: u64,
+    /// Requests which downloader middlewares sheduled again, e.g. redirects and retries.
+    pub requests_rescheduled: u64,
+    /// Responses by status code.
+    pub responses: BTreeMap<u16, u64>,
+    pub bytes_downloaded: u64,
+    /// Failed downloads.
+    pub errors: u64,
+    /// Items returned by the crawl.
+    pub items: u64,
+    /// Dropped requests, responses and items by reason.
+    pub dropped: BTreeMap<&'static str, u64>,
+    pub elapsed: Duration,
+}
+
+#[allow(dead_code)]
+impl StatsSnapshot {
+    /// Number of received responses.
+    pub fn pages(&self) -> u64 {
+        self.responses.values().sum()
+    }
+
+    /// Adds the counters of another crawl, the elapsed time is the longer one.
+    pub fn merge(&mut self, other: &StatsSnapshot) {
+        self.requests_sheduled += other.requests_sheduled;
+        self.requests_rescheduled += other.requests_rescheduled;
+        for (status, count) in &other.responses {
+            *self.responses.entry(*status).or_insert(0) += *count;
+        }
+        self.bytes_downloaded += other.bytes_downloaded;
+        self.errors += other.errors;
+        self.items += other.items;
+        for (reason, count) in &other.dropped {
+            *self.dropped.entry(*reason).or_insert(0) += *count;
+        }
+        self.elapsed = self.elapsed.max(other.elapsed);
+    }
+
+    pub fn log(&self, logger: &Logger) {
+        info!(logger, "crawl stats";
+              "requests_sheduled" => self.requests_sheduled,
+              "requests_rescheduled" => self.requests_rescheduled,
+              "responses" => self.pages(),
+              "bytes_downloaded" => self.bytes_downloaded,
+              "errors" => self.errors,
+              "items" => self.items,
+              "elapsed" => ?self.elapsed);
+        for (status, count) in &self.responses {
+            info!(logger, "responses by status"; "status" => status, "count" => count);
+        }
+        for (reason, count) in &self.dropped {
+            info!(logger, "dropped"; "reason" => reason, "count" => count);
+        }
+    }
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "requests sheduled: {}", self.requests_sheduled)?;
+        writeln!(f, "requests rescheduled: {}", self.requests_rescheduled)?;
+        for (status, count) in &self.responses {
+            writeln!(f, "responses {}: {}", status, count)?;
+        }
+        writeln!(f, "bytes downloaded: {}", self.bytes_downloaded)?;
+        writeln!(f, "errors: {}", self.errors)?;
+        writeln!(f, "items: {}", self.items)?;
+        for (reason, count) in &self.dropped {
+            writeln!(f, "dropped {}: {}", reason, count)?;
+        }
+        write!(f, "elapsed: {:?}", self.elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_resets_counters() {
+        let stats = Stats::new();
+        stats.request_sheduled();
+        stats.response_received(StatusCode::Ok, 10);
+        stats.item_scraped();
+        stats.dropped(DROP_OFFSITE);
+        stats.finish();
+        stats.start();
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.requests_sheduled, 0);
+        assert_eq!(snapshot.pages(), 0);
+        assert_eq!(snapshot.bytes_downloaded, 0);
+        assert_eq!(snapshot.items, 0);
+        assert!(snapshot.dropped.is_empty());
+    }
+}
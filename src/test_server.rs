@@ -0,0 +1,175 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use url::Url;
+
+/// HTTP server on a local port for tests, answers each request with the canned
+/// response of its target and closes the connection. Connections are served on
+/// their own threads.
+pub struct TestServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl TestServer {
+    /// Targets are matched exactly, e.g. `/robots.txt`, or `http://host/path` for
+    /// requests sent through a proxy. Unknown targets get a 404. Several responses
+    /// of a target are served in turn, the last one is repeated.
+    pub fn start(routes: Vec<(&'static str, String)>) -> Self {
+        Self::start_on("127.0.0.1", routes)
+    }
+
+    /// Listens on another loopback address, e.g. `127.0.0.2` to get a second host.
+    pub fn start_on(ip: &str, routes: Vec<(&'static str, String)>) -> Self {
+        let listener = TcpListener::bind((ip, 0)).expect("test server bind");
+        let addr = listener.local_addr().expect("test server address");
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        let routes = Arc::new(Mutex::new(routes));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let routes = routes.clone();
+                let seen = seen.clone();
+                thread::spawn(move || {
+                    let target = match read_request(&mut stream) {
+                        Some(target) => target,
+                        None => return,
+                    };
+                    seen.lock().unwrap().push(target.clone());
+                    let response = {
+                        let mut routes = routes.lock().unwrap();
+                        let matching: Vec<usize> = (0..routes.len())
+                            .filter(|&i| routes[i].0 == target)
+                            .collect();
+                        match matching.len() {
+                            0 => response("404 Not Found", &[], ""),
+                            1 => routes[matching[0]].1.clone(),
+                            _ => routes.remove(matching[0]).1,
+                        }
+                    };
+                    let _ = stream.write_all(response.as_bytes());
+                });
+            }
+        });
+        TestServer { addr, requests }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn url(&self, path: &str) -> Url {
+        format!("http://{}{}", self.addr, path).parse().unwrap()
+    }
+
+    /// Request targets in the order they were received.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Reads the request head and body, returns the request target.
+fn read_request<R: Read>(stream: R) -> Option<String> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let target = line.split_whitespace().nth(1)?.to_owned();
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        let mut split = header.splitn(2, ':');
+        let name = split.next()?.trim().to_lowercase();
+        if name == "content-length" {
+            length = split.next()?.trim().parse().ok()?;
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(target)
+}
+
+/// Raw HTTP response, e.g. `response("200 OK", &[("Location", "/")], "body")`.
+pub fn response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for &(name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    ));
+    response
+}
+
+/// SOCKS5 proxy for one connection, checks the `user:secret` credentials and that
+/// `example.com` is requested, then relays the connection to `target`.
+pub fn socks_proxy(target: SocketAddr) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("socks proxy bind");
+    let addr = listener.local_addr().expect("socks proxy address");
+    thread::spawn(move || {
+        let (mut client, _) = listener.accept().unwrap();
+        let mut head = [0; 2];
+        client.read_exact(&mut head).unwrap();
+        let mut methods = vec![0; head[1] as usize];
+        client.read_exact(&mut methods).unwrap();
+        assert!(methods.contains(&2), "username/password authentication not offered");
+        client.write_all(&[5, 2]).unwrap();
+        let mut username = [0; 2];
+        client.read_exact(&mut username).unwrap();
+        let mut username = vec![0; username[1] as usize];
+        client.read_exact(&mut username).unwrap();
+        let mut password = [0; 1];
+        client.read_exact(&mut password).unwrap();
+        let mut password = vec![0; password[0] as usize];
+        client.read_exact(&mut password).unwrap();
+        assert_eq!((&username[..], &password[..]), (&b"user"[..], &b"secret"[..]));
+        client.write_all(&[1, 0]).unwrap();
+        let mut connect = [0; 5];
+        client.read_exact(&mut connect).unwrap();
+        assert_eq!(&connect[..4], &[5, 1, 0, 3]);
+        let mut host = vec![0; connect[4] as usize + 2];
+        client.read_exact(&mut host).unwrap();
+        assert_eq!(&host[..host.len() - 2], b"example.com");
+        client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap();
+        let mut server = TcpStream::connect(target).unwrap();
+        let mut to_server = client.try_clone().unwrap();
+        let mut from_server = server.try_clone().unwrap();
+        thread::spawn(move || {
+            let _ = ::std::io::copy(&mut to_server, &mut server);
+        });
+        let _ = ::std::io::copy(&mut from_server, &mut client);
+    });
+    addr
+}
+
+/// Server which sends `head` on every connection and then stalls, e.g. an empty head
+/// never answers and a head with a `Content-Length` never sends the body.
+pub fn stalling_server(head: &'static str) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("stalling server bind");
+    let addr = listener.local_addr().expect("stalling server address");
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            thread::spawn(move || {
+                let _ = read_request(&mut stream);
+                let _ = stream.write_all(head.as_bytes());
+                thread::sleep(::std::time::Duration::from_secs(10));
+            });
+        }
+    });
+    addr
+}
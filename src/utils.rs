@@ -1,11 +1,14 @@
+use events::{Event, EventBus};
+use failure::Error;
 use futures::{Async, Future, Poll, Stream};
 use futures_cpupool::CpuPool;
 use request::Request;
+use seen::{SeenKey, SeenSet, SeenStorage};
 use sha1::{Digest, Sha1};
 use slog::Logger;
+use stats::{Stats, DROP_DUPLICATE_REQUEST};
 use std::borrow::Cow;
 use std::collections::BTreeMap;
-use std::collections::HashSet;
 use std::fmt;
 use std::iter::FromIterator;
 use std::sync::{Arc, Mutex, TryLockError};
@@ -94,26 +97,52 @@ pub(crate) fn canonicalize_url(url: &Url) -> CanonicalUrlView {
 }
 
 pub(crate) struct RFPFilter {
-    seen: Arc<Mutex<HashSet<RequestDigest>>>,
+    seen: Arc<Mutex<SeenSet<RequestDigest>>>,
     pool: CpuPool,
+    stats: Stats,
+    events: EventBus,
     logger: Option<Logger>,
 }
 
+#[allow(dead_code)]
 impl RFPFilter {
-    pub fn new(pool: CpuPool, logger: Option<Logger>) -> Self {
-        let seen = Arc::new(Mutex::new(HashSet::new()));
-        RFPFilter { seen, pool, logger }
+    pub fn new(pool: CpuPool, stats: Stats, events: EventBus, logger: Option<Logger>) -> Self {
+        let seen = Arc::new(Mutex::new(SeenSet::in_memory()));
+        RFPFilter {
+            seen,
+            pool,
+            stats,
+            events,
+            logger,
+        }
+    }
+
+    pub fn with_storage(
+        pool: CpuPool,
+        storage: &SeenStorage,
+        stats: Stats,
+        events: EventBus,
+        logger: Option<Logger>,
+    ) -> Result<Self, Error> {
+        let seen = Arc::new(Mutex::new(SeenSet::open(storage)?));
+        Ok(RFPFilter {
+            seen,
+            pool,
+            stats,
+            events,
+            logger,
+        })
     }
 }
 
 pub(crate) struct UniqueFuture {
-    seen: Arc<Mutex<HashSet<RequestDigest>>>,
+    seen: Arc<Mutex<SeenSet<RequestDigest>>>,
     digest: Option<RequestDigest>,
     request: Option<Request>,
 }
 
 impl Future for UniqueFuture {
-    type Item = (bool, Request);
+    type Item = (Result<bool, Error>, Request);
     type Error = !;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
@@ -129,10 +158,15 @@ impl Future for UniqueFuture {
                 let digest = self.digest
                     .take()
                     .expect("unique future poll called after ready");
-                let contains = seen.contains(&digest);
-                if !contains {
-                    seen.insert(digest);
-                }
+                let dont_filter = match self.request {
+                    Some(ref request) => request.dont_filter(),
+                    None => false,
+                };
+                let contains = if dont_filter {
+                    Ok(false)
+                } else {
+                    seen.check_and_insert(digest)
+                };
                 let request = self.request
                     .take()
                     .expect("unique future poll called after ready");
@@ -145,7 +179,7 @@ impl Future for UniqueFuture {
 
 impl UniqueFuture {
     fn new(
-        seen: Arc<Mutex<HashSet<RequestDigest>>>,
+        seen: Arc<Mutex<SeenSet<RequestDigest>>>,
         digest: RequestDigest,
         request: Request,
     ) -> Self {
@@ -166,6 +200,8 @@ impl RFPFilter {
     ) -> impl Stream<Item = Request, Error = !> {
         let seen = self.seen.clone();
         let pool = self.pool.clone();
+        let stats = self.stats.clone();
+        let events = self.events.clone();
         let logger = self.logger.clone();
         let stream = stream
             .and_then(move |(digest, request)| {
@@ -173,15 +209,26 @@ impl RFPFilter {
                 let fut = UniqueFuture::new(fut_seen, digest, request);
                 pool.spawn(fut)
             })
-            .filter_map(move |(contains, request)| {
-                if !contains {
-                    Some(request)
-                } else {
+            .filter_map(move |(contains, request)| match contains {
+                Ok(false) => Some(request),
+                Ok(true) => {
+                    stats.dropped(DROP_DUPLICATE_REQUEST);
+                    events.emit(|| Event::RequestDropped {
+                        url: Some(request.url().clone()),
+                        reason: DROP_DUPLICATE_REQUEST,
+                    });
                     if let Some(ref log) = logger {
                         info!(log, "request filtered"; "request" => %request);
                     }
                     None
                 }
+                Err(e) => {
+                    // Failing to persist the digest should not lose the request.
+                    if let Some(ref log) = logger {
+                        error!(log, "failed to remember request"; "request" => %request, "error" => %e);
+                    }
+                    Some(request)
+                }
             });
         stream
     }
@@ -190,10 +237,37 @@ impl RFPFilter {
 #[derive(PartialEq, Eq, Hash)]
 pub(crate) struct RequestDigest(Digest);
 
+impl fmt::Display for RequestDigest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl SeenKey for RequestDigest {
+    fn to_line(&self) -> String {
+        self.to_string()
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        line.parse().ok().map(RequestDigest)
+    }
+}
+
+/// Fingerprint of the request used by the dedup filter and the http cache.
+///
+/// The host and the port are part of the digest since the http cache was added,
+/// seen files of `RFPFilter::with_storage` written before don't match the new
+/// digests and should be removed, otherwise every request is crawled once more.
 pub(crate) fn calculate_digest(r: &Request) -> RequestDigest {
     let mut sha = Sha1::new();
     let canonical = canonicalize_url(r.url());
     sha.update(canonical.scheme.as_bytes());
+    if let Some(host) = canonical.host_str {
+        sha.update(host.as_bytes());
+    }
+    if let Some(port) = r.url().port_or_known_default() {
+        sha.update(port.to_string().as_bytes());
+    }
     sha.update(canonical.path.as_bytes());
     for (key, val) in canonical.query_pairs {
         sha.update(key.as_bytes());
@@ -0,0 +1,98 @@
+use ex_futures::stream::{Side, StreamExt, UnsyncFork};
+use futures::task::{self, Task};
+use futures::{Async, Poll, Stream};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+type Tasks = Rc<RefCell<[Option<Task>; 2]>>;
+
+/// Half of a stream split with `wake_fork`.
+pub(crate) struct WakeFork<S: Stream, F> {
+    fork: UnsyncFork<Waking<S>, F>,
+    index: usize,
+    tasks: Tasks,
+}
+
+impl<S, F, T> Stream for WakeFork<S, F>
+where
+    S: Stream,
+    S::Error: Clone,
+    F: FnMut(&S::Item) -> T,
+    T: Into<Side>,
+{
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.tasks.borrow_mut()[self.index] = Some(task::current());
+        self.fork.poll()
+    }
+}
+
+/// Wakes both halves up whenever the stream yields, the item may be queued for
+/// the half which didn't poll it.
+struct Waking<S> {
+    stream: S,
+    tasks: Tasks,
+}
+
+impl<S: Stream> Stream for Waking<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let poll = self.stream.poll();
+        if let Ok(Async::NotReady) = poll {
+            return poll;
+        }
+        for task in self.tasks.borrow().iter() {
+            if let Some(ref task) = *task {
+                if !task.will_notify_current() {
+                    task.notify();
+                }
+            }
+        }
+        poll
+    }
+}
+
+pub(crate) trait WakeForkExt: Stream + Sized {
+    /// Like `unsync_fork`, but the halves may be polled by different tasks, e.g. when
+    /// one of them is in a `FuturesUnordered`. `unsync_fork` only wakes the half which
+    /// polled the stream, so items routed to the other one could wait forever.
+    fn wake_fork<F, T>(self, router: F) -> (WakeFork<Self, F>, WakeFork<Self, F>)
+    where
+        Self::Error: Clone,
+        F: FnMut(&Self::Item) -> T,
+        Side: From<T>;
+}
+
+impl<S> WakeForkExt for S
+where
+    S: Stream + Sized,
+{
+    fn wake_fork<F, T>(self, router: F) -> (WakeFork<Self, F>, WakeFork<Self, F>)
+    where
+        Self::Error: Clone,
+        F: FnMut(&Self::Item) -> T,
+        Side: From<T>,
+    {
+        let tasks: Tasks = Rc::new(RefCell::new([None, None]));
+        let stream = Waking {
+            stream: self,
+            tasks: tasks.clone(),
+        };
+        let (left, right) = stream.unsync_fork(router);
+        let left = WakeFork {
+            fork: left,
+            index: 0,
+            tasks: tasks.clone(),
+        };
+        let right = WakeFork {
+            fork: right,
+            index: 1,
+            tasks,
+        };
+        (left, right)
+    }
+}